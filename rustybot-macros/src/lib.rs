@@ -3,14 +3,16 @@ use quote::quote;
 
 /// This procedural macro will introduce a mutable
 /// [`PoolConnection`][`sqlx::pool::PoolConnection`] instance called
-/// `connection`. So you can just operate on that. Such as making queries.
+/// `connection`, acquired from the given pool. So you can just operate on
+/// that. Such as making queries.
+///
+/// The enclosing function must return a `Result`, failing to acquire a
+/// connection is returned as an error.
 #[proc_macro]
-pub fn get_connection(_item: TokenStream) -> TokenStream {
+pub fn get_connection(item: TokenStream) -> TokenStream {
+    let pool = proc_macro2::TokenStream::from(item);
     quote! {
-      use crate::DB_POOL;
-      let pool_guard = DB_POOL.lock().await;
-      let mut connection = pool_guard.as_ref().unwrap().acquire().await.unwrap();
-      drop(pool_guard);
+      let mut connection = #pool.acquire().await?;
     }
     .into()
 }
@@ -1,6 +1,8 @@
 use sha2::{Digest, Sha512};
 use std::path::PathBuf;
 
+use crate::models::Store;
+
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct AuthUser {
     pub id: String,
@@ -50,14 +52,13 @@ pub fn auth_with_file(id: &str, hash: &str, salt: &str) -> bool {
         }
     }
     log::warn!(target: "app", "Authentication failed");
-    return false;
+    false
 }
 
-pub async fn auth_with_db(id: &str, hash: &str, salt: &str) -> bool {
-    use crate::models::Auth;
-    if let Ok(auth) = Auth::auth(id).await {
+pub async fn auth_with_db(store: &Store, id: &str, hash: &str, salt: &str) -> bool {
+    if let Ok(auth) = store.auths.find_by_name(id).await {
         if let Some(auth) = auth {
-            if auth.hash(salt).await == hash {
+            if auth.hash(id, salt) == hash {
                 log::debug!(target:"app", "Authentication passed");
                 return true;
             } else {
@@ -66,9 +67,9 @@ pub async fn auth_with_db(id: &str, hash: &str, salt: &str) -> bool {
         } else {
             log::warn!(target: "app", "Authentication failed due to no auth info of user `{}` found", id);
         }
-        return false;
+        false
     } else {
         log::warn!(target: "app", "Authentication failed due to database query failed.");
-        return false;
+        false
     }
 }
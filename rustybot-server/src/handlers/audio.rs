@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::{
+    multipart::{Form, Part},
+    Method,
+};
+
+use crate::{
+    handlers::{admit, charge, current_user, owned_chat, requested_chat, with_message_headers},
+    media::{content_type_of, MediaService},
+    models::{MediaType, Message, MessageMedia, MessageModel, MessageSender, QuotaType, Store},
+    request::RemoteClient,
+    types::error::error_response,
+    utils::reload::LiveConfig,
+};
+
+/// Body of a speech synthesis request. Either `msg_id` or `input` is needed.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SpeechRequest {
+    /// Assistant message to read out loud.
+    pub msg_id: Option<i32>,
+
+    /// Arbitrary text to read out loud, ignored when `msg_id` is given.
+    pub input: Option<String>,
+
+    #[serde(default = "SpeechRequest::default_model")]
+    pub model: String,
+
+    #[serde(default = "SpeechRequest::default_voice")]
+    pub voice: String,
+
+    #[serde(default = "SpeechRequest::default_format")]
+    pub response_format: String,
+
+    pub speed: Option<f32>,
+}
+
+impl SpeechRequest {
+    fn default_model() -> String {
+        "tts-1".to_string()
+    }
+
+    fn default_voice() -> String {
+        "alloy".to_string()
+    }
+
+    fn default_format() -> String {
+        "mp3".to_string()
+    }
+}
+
+/// Synthesise speech with `/v1/audio/speech`.
+///
+/// When reading a message, the audio is attached to it as an `Audio` media.
+/// Text-to-speech quota is charged by character count.
+pub async fn speech(
+    req: HttpRequest,
+    data: web::Json<SpeechRequest>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let data = data.into_inner();
+
+    // The message content is what is charged, so it is looked up before the
+    // quota check. Messages of other users are reported as missing, so ids
+    // cannot be probed.
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let message = if let Some(msg_id) = data.msg_id {
+        let message = match store.messages.find_by_id(msg_id).await {
+            Ok(message) => message,
+            Err(e) => {
+                log::error!(target: "app", "Unable to query message `{msg_id}`: {e}");
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to query message",
+                );
+            }
+        };
+        let owned = match message.as_ref() {
+            Some(message) => match owned_chat(&store, &user, message.msg_chat_id).await {
+                Ok(_) => true,
+                Err(resp) if resp.status() == StatusCode::NOT_FOUND => false,
+                Err(resp) => return resp,
+            },
+            None => false,
+        };
+        if !owned {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                format!("Message `{msg_id}` not found"),
+            );
+        }
+        message
+    } else {
+        None
+    };
+    if let Some(message) = message.as_ref() {
+        if !matches!(message.msg_sender, MessageSender::Assistant) {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "Only assistant messages can be read out loud",
+            );
+        }
+    }
+    let input = match (message.as_ref(), data.input.as_ref()) {
+        (Some(message), _) => message.msg_content.clone(),
+        (None, Some(input)) => input.clone(),
+        (None, None) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "Either `msg_id` or `input` is required",
+            )
+        }
+    };
+    let characters = input.chars().count() as i32;
+
+    if let Err(resp) = admit(
+        &req,
+        &store,
+        &config,
+        &data.model,
+        QuotaType::TextToSpeech,
+        characters,
+    )
+    .await
+    {
+        return resp;
+    }
+
+    let mut body = serde_json::json!({
+        "model": data.model,
+        "input": input,
+        "voice": data.voice,
+        "response_format": data.response_format,
+    });
+    if let Some(speed) = data.speed {
+        body["speed"] = serde_json::json!(speed);
+    }
+    let (mut resp_builder, bytes) = match remote
+        .call(Method::POST, "/v1/audio/speech", |req| req.json(&body))
+        .await
+    {
+        Ok(result) => result,
+        Err(resp) => return resp,
+    };
+
+    charge(&store, &user, QuotaType::TextToSpeech, characters).await;
+
+    let Some(mut message) = message else {
+        return resp_builder.body(bytes);
+    };
+    let Some(url) = media.put_or_embed(bytes.clone(), &MediaType::Audio).await else {
+        log::warn!(target: "app", "Speech in `{}` format is not kept", data.response_format);
+        return with_message_headers(
+            resp_builder.body(bytes),
+            message.msg_chat_id,
+            message.msg_id.unwrap(),
+        );
+    };
+    message.set_media(
+        &format!("speech_{}", data.voice),
+        MessageMedia {
+            ty: MediaType::Audio,
+            url,
+        },
+    );
+    if let Err(e) = store.messages.update_medias(&message).await {
+        log::error!(target: "app", "Unable to attach speech to message `{}`: {e}", message.msg_id.unwrap());
+    }
+
+    with_message_headers(
+        resp_builder.body(bytes),
+        message.msg_chat_id,
+        message.msg_id.unwrap(),
+    )
+}
+
+/// Fields of a transcription upload passed on to OpenAI besides the file.
+const UPLOAD_FIELDS: [&str; 4] = ["model", "language", "prompt", "temperature"];
+
+/// Audio upload and its accompanying fields.
+struct TranscriptionUpload {
+    audio: Vec<u8>,
+    filename: String,
+    mime: String,
+    fields: HashMap<String, String>,
+}
+
+/// Read a transcription upload of at most `limit` bytes, all fields
+/// together. Fields other than `file` and [`UPLOAD_FIELDS`] are skipped.
+async fn read_upload(
+    mut payload: Multipart,
+    limit: usize,
+) -> Result<TranscriptionUpload, HttpResponse> {
+    let invalid = |e: actix_multipart::MultipartError| {
+        error_response(StatusCode::BAD_REQUEST, format!("Invalid upload: {e}"))
+    };
+
+    let mut upload = TranscriptionUpload {
+        audio: vec![],
+        filename: String::new(),
+        mime: String::new(),
+        fields: HashMap::new(),
+    };
+    let mut total = 0;
+    while let Some(mut field) = payload.try_next().await.map_err(invalid)? {
+        let name = field.name().to_string();
+        let keep = name == "file" || UPLOAD_FIELDS.contains(&name.as_str());
+        let mut content: Vec<u8> = vec![];
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(invalid)?;
+            total += chunk.len();
+            if total > limit {
+                return Err(error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Upload exceeds {limit} bytes"),
+                ));
+            }
+            if keep {
+                content.extend_from_slice(&chunk);
+            }
+        }
+
+        if name == "file" {
+            upload.filename = field
+                .content_disposition()
+                .get_filename()
+                .unwrap_or("audio.mp3")
+                .to_string();
+            upload.mime = field
+                .content_type()
+                .map(|mime| mime.to_string())
+                .unwrap_or_else(|| "audio/mpeg".to_string());
+            upload.audio = content;
+        } else if keep {
+            upload
+                .fields
+                .insert(name, String::from_utf8_lossy(&content).to_string());
+        }
+    }
+
+    if upload.audio.is_empty() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing `file` in upload",
+        ));
+    }
+    if content_type_of(&upload.audio, &MediaType::Audio).is_none() {
+        return Err(error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported audio format",
+        ));
+    }
+    Ok(upload)
+}
+
+/// Transcribe an uploaded audio with `/v1/audio/transcriptions`.
+///
+/// The transcript is saved as a user message of the chat given by
+/// `x-rustybot-chat-id`, with the original audio attached to it. The audio
+/// is kept in the media store, so transcriptions are refused while it is
+/// unavailable. Transcription quota is charged per upload.
+pub async fn transcriptions(
+    req: HttpRequest,
+    payload: Multipart,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let upload = match read_upload(payload, config.limits.audio_upload).await {
+        Ok(upload) => upload,
+        Err(resp) => return resp,
+    };
+    let model = upload
+        .fields
+        .get("model")
+        .cloned()
+        .unwrap_or_else(|| "whisper-1".to_string());
+
+    let user = match admit(&req, &store, &config, &model, QuotaType::Transcription, 1).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let mut chat = match requested_chat(&req, &store, &user).await {
+        Ok(chat) => chat,
+        Err(resp) => return resp,
+    };
+    let chat_id = chat.chat_id.unwrap();
+
+    let url = match media
+        .put(upload.audio.clone().into(), &MediaType::Audio)
+        .await
+    {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!(target: "app", "Unable to store uploaded audio: {e}");
+            return error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Media store is unavailable",
+            );
+        }
+    };
+
+    let build = |req: reqwest::RequestBuilder| {
+        let mut form = Form::new()
+            .text("model", model.clone())
+            .text("response_format", "json");
+        for name in ["language", "prompt", "temperature"] {
+            if let Some(value) = upload.fields.get(name) {
+                form = form.text(name, value.clone());
+            }
+        }
+        let file = Part::bytes(upload.audio.clone()).file_name(upload.filename.clone());
+        let file = match file.mime_str(&upload.mime) {
+            Ok(file) => file,
+            Err(_) => Part::bytes(upload.audio.clone()).file_name(upload.filename.clone()),
+        };
+        req.multipart(form.part("file", file))
+    };
+    let (_, bytes) = match remote
+        .call(Method::POST, "/v1/audio/transcriptions", build)
+        .await
+    {
+        Ok(result) => result,
+        Err(resp) => return resp,
+    };
+
+    let transcript = match serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|result| result.get("text")?.as_str().map(|text| text.to_string()))
+    {
+        Some(transcript) => transcript,
+        None => {
+            log::error!(target: "openai", "Unable to parse transcription response");
+            return error_response(
+                StatusCode::BAD_GATEWAY,
+                "Unable to parse upstream transcript",
+            );
+        }
+    };
+
+    let medias = HashMap::from([(
+        "audio".to_string(),
+        MessageMedia {
+            ty: MediaType::Audio,
+            url,
+        },
+    )]);
+    let message = match chat
+        .append(
+            &store,
+            Message::new(
+                chat_id,
+                MessageModel::Others,
+                MessageSender::User,
+                transcript.clone(),
+                Some(medias),
+            ),
+        )
+        .await
+    {
+        Ok(message) => message,
+        Err(e) => {
+            log::error!(target: "app", "Unable to save transcript to chat `{chat_id}`: {e}");
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save transcript",
+            );
+        }
+    };
+    log::debug!(target: "app", "User message ID: `{}` of chat ID `{}` saved to database", message.msg_id.unwrap(), chat_id);
+
+    charge(&store, &user, QuotaType::Transcription, 1).await;
+
+    with_message_headers(
+        HttpResponse::Ok().json(serde_json::json!({ "text": transcript })),
+        chat_id,
+        message.msg_id.unwrap(),
+    )
+}
@@ -0,0 +1,467 @@
+use std::collections::HashSet;
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use reqwest::Method;
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    handlers::{
+        admit, charge, current_user, owned_chat, owned_medias, with_message_headers,
+        with_reply_header,
+    },
+    media::MediaService,
+    metrics,
+    middleware::TokenUsage,
+    models::{Chat, Message, MessageModel, MessageSender, QuotaType, Store, User},
+    request::RemoteClient,
+    types::{
+        chat::{ChatCompletionRequest, ChatRequestMessage, ContentPart, MessageContent},
+        error::error_response,
+    },
+    utils::{config::Config, logging::redact, reload::LiveConfig, tasks},
+};
+
+const ENDPOINT: &str = "/v1/chat/completions";
+
+/// Shape request messages for the selected model. Vision models get images
+/// of user messages as `image_url` parts, other models plain text only.
+/// Stored medias are only sent when in `owned`.
+pub async fn prepare_messages(
+    data: &mut ChatCompletionRequest,
+    vision: bool,
+    media: &MediaService,
+    owned: &HashSet<String>,
+) {
+    for message in data.messages.iter_mut() {
+        if !vision || message.role != "user" {
+            if let MessageContent::Parts(_) = message.content {
+                message.content = MessageContent::Text(message.content.to_text());
+            }
+            continue;
+        }
+        if !message.content.has_images() {
+            continue;
+        }
+
+        let mut parts = message.content.to_parts();
+        for part in parts.iter_mut() {
+            if let ContentPart::ImageUrl { image_url } = part {
+                image_url.url = media.public_url(&image_url.url, owned).await;
+            }
+        }
+        message.content = MessageContent::Parts(parts);
+    }
+}
+
+/// Forward a chat completion upstream and save the reply after message
+/// `prompt_id`, on the selected branch of `chat`.
+pub async fn reply(
+    remote: &RemoteClient,
+    store: &Store,
+    data: &ChatCompletionRequest,
+    mut chat: Chat,
+    prompt_id: i32,
+) -> HttpResponse {
+    let chat_id = chat.chat_id.unwrap();
+    let current_model: MessageModel = data.model.as_str().into();
+
+    if data.stream.is_none() || data.stream == Some(false) {
+        // No stream mode
+        let (mut resp_builder, bytes) = match remote
+            .call(Method::POST, ENDPOINT, |req| req.json(data))
+            .await
+        {
+            Ok(result) => result,
+            Err(resp) => return resp,
+        };
+        let completion = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default();
+        metrics::record_usage(&data.model, chat.chat_user_id, &completion["usage"]);
+        let completion_message = completion["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        if completion_message.is_empty() {
+            log::warn!(target: "app", "Empty assistant reply in chat ID `{}` not saved", chat_id);
+            return resp_builder.body(bytes);
+        }
+
+        let reply = Message::new(
+            chat_id,
+            current_model,
+            MessageSender::Assistant,
+            completion_message,
+            None,
+        );
+        let mut resp = resp_builder.body(bytes);
+        resp.extensions_mut()
+            .insert(TokenUsage::of(&completion["usage"]));
+        return match chat.branch(store, Some(prompt_id), reply).await {
+            Ok(reply) => {
+                log::debug!(target: "app", "Assistant message ID: `{}` of chat ID `{}` saved to database", reply.msg_id.unwrap(), chat_id);
+                with_reply_header(resp, reply.msg_id.unwrap())
+            }
+            Err(e) => {
+                log::error!(target: "app", "Unable to save assistant reply in chat ID `{}`: {e}", chat_id);
+                resp
+            }
+        };
+    }
+
+    // Stream mode
+    let (sender, mut receiver) = channel::<Bytes>(1024);
+    let model = data.model.clone();
+    let store = store.clone();
+    // Tracked so a reply still streaming at shutdown is saved before exit.
+    tasks::spawn(async move {
+        let mut completion_message = String::new();
+        // Chunks may end in the middle of a line, even of a character, so
+        // bytes are kept until their line is complete.
+        let mut pending: Vec<u8> = Vec::new();
+        while let Some(bytes) = receiver.recv().await {
+            if bytes.as_ref() == b"EOS__EOS" {
+                break;
+            }
+            pending.extend_from_slice(&bytes);
+            let Some(end) = pending.iter().rposition(|byte| *byte == b'\n') else {
+                continue;
+            };
+            let lines: Vec<u8> = pending.drain(..=end).collect();
+            let chunk_data_raw = String::from_utf8_lossy(&lines);
+
+            log::debug!(
+                target: "openai",
+                "BYTES FROM STREAM AFTER MESSAGE ID `{}`: {}",
+                prompt_id,
+                redact(&chunk_data_raw)
+            );
+
+            for chunk_data in chunk_data_raw.split('\n') {
+                let chunk_data = chunk_data.trim().to_string();
+                if &chunk_data == "data: [DONE]" {
+                    log::debug!(target: "openai", "Last chunk received.");
+                    break;
+                }
+                if let Some(stripped_chunk) = chunk_data.strip_prefix("data: ") {
+                    // Parsed loosely, models unknown to `rust_ai` stream too.
+                    if let Ok(message_chunk) =
+                        serde_json::from_str::<serde_json::Value>(stripped_chunk)
+                    {
+                        // Only sent with `stream_options.include_usage`.
+                        metrics::record_usage(&model, chat.chat_user_id, &message_chunk["usage"]);
+                        completion_message.push_str(
+                            message_chunk["choices"]
+                                .as_array()
+                                .and_then(|choices| choices.last())
+                                .and_then(|choice| choice["delta"]["content"].as_str())
+                                .unwrap_or_default(),
+                        );
+                    }
+                }
+            }
+        }
+        if completion_message.is_empty() {
+            // Upstream failed before sending anything, nothing to keep.
+            log::warn!(target: "app", "Empty assistant reply in chat ID `{}` not saved", chat_id);
+            return;
+        }
+        // Save response to database.
+        let reply = Message::new(
+            chat_id,
+            current_model,
+            MessageSender::Assistant,
+            completion_message,
+            None,
+        );
+        match chat.branch(&store, Some(prompt_id), reply).await {
+            Ok(reply) => {
+                log::debug!(target: "app", "Assistant message ID: `{}` of chat ID `{}` saved to database", reply.msg_id.unwrap(), chat_id)
+            }
+            Err(e) => {
+                log::error!(target: "app", "Unable to save assistant reply in chat ID `{}`: {e}", chat_id)
+            }
+        }
+    });
+
+    remote
+        .post_remote_stream(ENDPOINT, data, Some(sender))
+        .await
+}
+
+/// Body of an edit or a regeneration. Everything but `content` is passed to
+/// upstream like a chat completion, the messages being the chat history.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BranchRequest {
+    pub model: String,
+
+    /// New content of the edited message.
+    #[serde(default)]
+    pub content: Option<MessageContent>,
+
+    #[serde(default)]
+    pub stream: Option<bool>,
+
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Message `msg_id` together with its chat, which must belong to `user`.
+async fn owned_message(
+    store: &Store,
+    user: &User,
+    msg_id: i32,
+) -> Result<(Message, Chat), HttpResponse> {
+    let message = match store.messages.find_by_id(msg_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return Err(error_response(
+                StatusCode::NOT_FOUND,
+                format!("Message `{msg_id}` not found"),
+            ))
+        }
+        Err(e) => {
+            log::error!(target: "app", "Unable to query message `{msg_id}`: {e}");
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to query message",
+            ));
+        }
+    };
+    let chat = owned_chat(store, user, message.msg_chat_id).await?;
+    Ok((message, chat))
+}
+
+/// Ask upstream for a new reply to message `prompt_id`, the last message of
+/// the selected branch when `None`. The reply's branch gets selected once it
+/// is saved.
+async fn reply_to_history(
+    data: BranchRequest,
+    chat: Chat,
+    prompt_id: Option<i32>,
+    remote: &RemoteClient,
+    store: &Store,
+    config: &Config,
+    media: &MediaService,
+) -> HttpResponse {
+    let vision = config.models.supports_vision(&data.model);
+    let history = match prompt_id {
+        Some(prompt_id) => chat.history_to(store, prompt_id).await,
+        None => chat.history(store).await,
+    };
+    let Some(prompt_id) = history.last().and_then(|msg| msg.msg_id) else {
+        return error_response(StatusCode::BAD_REQUEST, "Nothing to reply to");
+    };
+
+    let mut request = ChatCompletionRequest {
+        model: data.model,
+        messages: history
+            .iter()
+            .map(|msg| ChatRequestMessage {
+                role: msg.msg_sender.role().to_string(),
+                content: msg.content(vision),
+                extra: serde_json::Map::new(),
+            })
+            .collect(),
+        stream: data.stream,
+        extra: data.extra,
+    };
+    let urls = request.image_urls();
+    let owned = owned_medias(
+        store,
+        media,
+        chat.chat_user_id,
+        urls.iter().map(String::as_str),
+    )
+    .await;
+    prepare_messages(&mut request, vision, media, &owned).await;
+
+    let chat_id = chat.chat_id.unwrap();
+    with_message_headers(
+        reply(remote, store, &request, chat, prompt_id).await,
+        chat_id,
+        prompt_id,
+    )
+}
+
+/// Edit a user message: its new version is saved as a branch next to it and
+/// answered. The new branch is selected once the reply is saved.
+pub async fn edit(
+    req: HttpRequest,
+    msg_id: web::Path<i32>,
+    data: web::Json<BranchRequest>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let mut data = data.into_inner();
+    let Some(content) = data.content.take() else {
+        return error_response(StatusCode::BAD_REQUEST, "Missing `content` in request body");
+    };
+    let user = match admit(
+        &req,
+        &store,
+        &config,
+        &data.model,
+        QuotaType::ChatCompletion,
+        1,
+    )
+    .await
+    {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let (message, chat) = match owned_message(&store, &user, *msg_id).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+    if !matches!(message.msg_sender, MessageSender::User) {
+        return error_response(StatusCode::BAD_REQUEST, "Only user messages can be edited");
+    }
+
+    let mut edited = Message::from_content(
+        message.msg_chat_id,
+        data.model.as_str().into(),
+        MessageSender::User,
+        &content,
+    );
+    edited.msg_parent_id = message.msg_parent_id;
+    let owned = owned_medias(&store, &media, chat.chat_user_id, edited.media_urls()).await;
+    media.store_medias(&mut edited, &owned).await;
+    // The edit is saved next to the message without selecting its branch,
+    // the reply selects it once saved so a failed edit changes nothing.
+    let edited = match store.messages.save(&edited).await {
+        Ok(edited) => edited,
+        Err(e) => {
+            log::error!(target: "app", "Unable to save edit of message `{}`: {e}", *msg_id);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save message");
+        }
+    };
+    log::debug!(target: "app", "User message ID: `{}` of chat ID `{}` saved to database", edited.msg_id.unwrap(), message.msg_chat_id);
+
+    let resp = reply_to_history(data, chat, edited.msg_id, &remote, &store, &config, &media).await;
+    if resp.status().is_success() {
+        charge(&store, &user, QuotaType::ChatCompletion, 1).await;
+    }
+    resp
+}
+
+/// Regenerate an assistant reply: the new reply is saved as a branch next to
+/// it.
+pub async fn regenerate(
+    req: HttpRequest,
+    msg_id: web::Path<i32>,
+    data: web::Json<BranchRequest>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let data = data.into_inner();
+    let user = match admit(
+        &req,
+        &store,
+        &config,
+        &data.model,
+        QuotaType::ChatCompletion,
+        1,
+    )
+    .await
+    {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let (message, chat) = match owned_message(&store, &user, *msg_id).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+    let (MessageSender::Assistant, Some(parent_id)) = (&message.msg_sender, message.msg_parent_id)
+    else {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Only assistant replies can be regenerated",
+        );
+    };
+
+    // The new reply branches off from the prompt. The selected branch is
+    // left as is until it is saved, so a failed regeneration changes nothing.
+    let resp = reply_to_history(
+        data,
+        chat,
+        Some(parent_id),
+        &remote,
+        &store,
+        &config,
+        &media,
+    )
+    .await;
+    if resp.status().is_success() {
+        charge(&store, &user, QuotaType::ChatCompletion, 1).await;
+    }
+    resp
+}
+
+/// Every version of a message, oldest first.
+pub async fn versions(
+    req: HttpRequest,
+    msg_id: web::Path<i32>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let (message, _) = match owned_message(&store, &user, *msg_id).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+    match store.messages.versions(&message).await {
+        Ok(versions) => HttpResponse::Ok().json(versions),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query versions of message `{}`: {e}", *msg_id);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to query message")
+        }
+    }
+}
+
+/// Switch to the branch going through a message, returning the new history.
+pub async fn select(
+    req: HttpRequest,
+    msg_id: web::Path<i32>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let (_, mut chat) = match owned_message(&store, &user, *msg_id).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+    if let Err(e) = chat.select(&store, *msg_id).await {
+        log::error!(target: "app", "Unable to select message `{}`: {e}", *msg_id);
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to select message",
+        );
+    }
+    HttpResponse::Ok().json(chat.history(&store).await)
+}
+
+/// Messages of the selected branch of a chat.
+pub async fn history(
+    req: HttpRequest,
+    chat_id: web::Path<i32>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    match owned_chat(&store, &user, *chat_id).await {
+        Ok(chat) => HttpResponse::Ok().json(chat.history(&store).await),
+        Err(resp) => resp,
+    }
+}
@@ -0,0 +1,182 @@
+use std::io::Write;
+
+use actix_web::{
+    http::{
+        header::{ContentDisposition, DispositionParam, DispositionType},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    handlers::{current_user, owned_chat},
+    models::{Chat, MessageSender, Store},
+    types::error::error_response,
+};
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Readable transcript of the selected branch, medias expanded.
+    #[default]
+    #[serde(alias = "md")]
+    Markdown,
+
+    /// Every `Chat` and `Message` row, all branches included.
+    Json,
+
+    /// OpenAI fine-tuning JSONL of the selected branch.
+    #[serde(alias = "fine-tune")]
+    Jsonl,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Jsonl => "application/jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// Render a chat in the given format.
+async fn render(
+    store: &Store,
+    chat: &Chat,
+    format: ExportFormat,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let chat_id = chat.chat_id.ok_or("Chat not saved to database yet")?;
+    Ok(match format {
+        ExportFormat::Markdown => {
+            let mut markdown = format!(
+                "# {}\n\n_{}_\n",
+                chat.chat_summary
+                    .clone()
+                    .unwrap_or_else(|| format!("Chat {chat_id}")),
+                chat.chat_created_at.format("%F %T UTC")
+            );
+            for message in chat.history(store).await {
+                let sender = match message.msg_sender {
+                    MessageSender::User => "User",
+                    MessageSender::Assistant => "Assistant",
+                    MessageSender::System => "System",
+                };
+                markdown.push_str(&format!("\n## {sender}\n\n{}\n", message.message()));
+            }
+            markdown
+        }
+        ExportFormat::Json => serde_json::to_string_pretty(&serde_json::json!({
+            "chat": chat,
+            "messages": store.messages.find_by_chat(chat_id).await?,
+        }))?,
+        ExportFormat::Jsonl => {
+            let messages: Vec<serde_json::Value> = chat
+                .history(store)
+                .await
+                .iter()
+                .map(|message| {
+                    serde_json::json!({
+                        "role": message.msg_sender.role(),
+                        "content": message.message(),
+                    })
+                })
+                .collect();
+            format!("{}\n", serde_json::json!({ "messages": messages }))
+        }
+    })
+}
+
+fn attachment(filename: String) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename)],
+    }
+}
+
+/// Export one chat as `/v1/chats/{chat_id}/export?format=markdown|json|jsonl`.
+pub async fn chat(
+    req: HttpRequest,
+    chat_id: web::Path<i32>,
+    query: web::Query<ExportQuery>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let chat = match owned_chat(&store, &user, *chat_id).await {
+        Ok(chat) => chat,
+        Err(resp) => return resp,
+    };
+
+    match render(&store, &chat, query.format).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(query.format.content_type())
+            .insert_header(attachment(format!(
+                "chat-{}.{}",
+                *chat_id,
+                query.format.extension()
+            )))
+            .body(body),
+        Err(e) => {
+            log::error!(target: "app", "Unable to export chat `{}`: {e}", *chat_id);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to export chat")
+        }
+    }
+}
+
+/// Export every chat of the caller as a zip, one file per chat.
+pub async fn all(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    let archive = async {
+        let mut zip = ZipWriter::new(std::io::Cursor::new(vec![]));
+        for chat in store.chats.find_by_user(user.id().unwrap()).await? {
+            let body = render(&store, &chat, query.format).await?;
+            zip.start_file(
+                format!(
+                    "chat-{}.{}",
+                    chat.chat_id.unwrap(),
+                    query.format.extension()
+                ),
+                FileOptions::default(),
+            )?;
+            zip.write_all(body.as_bytes())?;
+        }
+        Ok::<_, Box<dyn std::error::Error>>(zip.finish()?.into_inner())
+    };
+
+    match archive.await {
+        Ok(archive) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header(attachment(format!("chats-{}.zip", user.name())))
+            .body(archive),
+        Err(e) => {
+            log::error!(target: "app", "Unable to export chats of user `{}`: {e}", user.name());
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to export chats")
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    metrics, models::Store, request::RemoteClient, types::error::error_response,
+    utils::reload::LiveConfig,
+};
+
+/// Longest a readiness check waits for the database.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness, `/healthz`: the process answers.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness, `/readyz`: the database answers and upstream is configured.
+/// Fails with 503 and the failed checks otherwise.
+pub async fn readyz(
+    config: web::Data<LiveConfig>,
+    remote: web::Data<RemoteClient>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    // Database errors are logged only, they may tell where it lives.
+    let database_ready = match database_check(&store).await {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!(target: "app", "Readiness check failed: database {e}");
+            false
+        }
+    };
+    let upstream = match config.get().validate() {
+        Ok(()) => serde_json::json!({
+            "ok": true,
+            "available_keys": remote
+                .key_health()
+                .iter()
+                .filter(|key| key.cooldown_remaining.is_none())
+                .count(),
+        }),
+        Err(e) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+    };
+
+    let ready = database_ready && upstream["ok"] == true;
+    let body = serde_json::json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "checks": {
+            "database": if database_ready { "ok" } else { "unavailable" },
+            "upstream": upstream,
+        },
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn database_check(store: &Store) -> Result<(), String> {
+    // Nothing to wait for without a database.
+    let Some(pool) = store.pool() else {
+        return Ok(());
+    };
+    match tokio::time::timeout(DB_CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(pool)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+/// Prometheus metrics, `/metrics`, for scrapers sending
+/// `Authorization: Bearer <server.metrics_token>`. Not served without a
+/// configured token.
+pub async fn metrics(
+    req: HttpRequest,
+    config: web::Data<LiveConfig>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let Some(token) = config.get().server.metrics_token.clone() else {
+        return error_response(StatusCode::NOT_FOUND, "Metrics are not enabled");
+    };
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Digests are compared so the time taken does not tell how much of the
+    // token was right.
+    if bearer.map(Sha256::digest) != Some(Sha256::digest(&token)) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid metrics token");
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(store.pool()))
+}
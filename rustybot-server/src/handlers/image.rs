@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use reqwest::Method;
+
+use crate::{
+    handlers::{admit, charge, requested_chat, with_message_headers, with_reply_header},
+    media::MediaService,
+    models::{MediaType, Message, MessageMedia, MessageModel, MessageSender, QuotaType, Store},
+    request::RemoteClient,
+    types::error::error_response,
+    utils::reload::LiveConfig,
+};
+
+/// Most images OpenAI generates for one request.
+const MAX_IMAGES: i64 = 10;
+
+/// Generate images with `/v1/images/generations`.
+///
+/// The prompt is saved as a user message of the chat given by
+/// `x-rustybot-chat-id`, the generated images as medias of an assistant reply
+/// to it. Images are copied to the media store as upstream URLs expire. One
+/// unit of image generation quota is charged per image produced.
+pub async fn generations(
+    req: HttpRequest,
+    data: web::Json<serde_json::Value>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let prompt = match data.get("prompt").and_then(|prompt| prompt.as_str()) {
+        Some(prompt) => prompt.to_string(),
+        None => return error_response(StatusCode::BAD_REQUEST, "Missing `prompt` in request body"),
+    };
+    let model = data
+        .get("model")
+        .and_then(|model| model.as_str())
+        .unwrap_or("dall-e-2")
+        .to_string();
+    let n = match data.get("n").filter(|n| !n.is_null()) {
+        None => 1,
+        Some(n) => match n.as_i64().filter(|n| (1..=MAX_IMAGES).contains(n)) {
+            Some(n) => n as i32,
+            None => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("`n` must be between 1 and {MAX_IMAGES}"),
+                )
+            }
+        },
+    };
+
+    let user = match admit(&req, &store, &config, &model, QuotaType::ImageGeneration, n).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let mut chat = match requested_chat(&req, &store, &user).await {
+        Ok(chat) => chat,
+        Err(resp) => return resp,
+    };
+    let chat_id = chat.chat_id.unwrap();
+
+    let prompt_msg = match chat
+        .append(
+            &store,
+            Message::new(
+                chat_id,
+                MessageModel::Others,
+                MessageSender::User,
+                prompt,
+                None,
+            ),
+        )
+        .await
+    {
+        Ok(prompt_msg) => prompt_msg,
+        Err(e) => {
+            log::error!(target: "app", "Unable to save prompt to chat `{chat_id}`: {e}");
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save message");
+        }
+    };
+    log::debug!(target: "app", "User message ID: `{}` of chat ID `{}` saved to database", prompt_msg.msg_id.unwrap(), chat_id);
+
+    let (mut resp_builder, bytes) = match remote
+        .call(Method::POST, "/v1/images/generations", |req| {
+            req.json(&data)
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(resp) => return with_message_headers(resp, chat_id, prompt_msg.msg_id.unwrap()),
+    };
+
+    let images: serde_json::Value = match serde_json::from_slice(&bytes) {
+        Ok(images) => images,
+        Err(e) => {
+            log::error!(target: "openai", "Unable to parse image generation response: {e}");
+            return error_response(StatusCode::BAD_GATEWAY, "Unable to parse upstream images");
+        }
+    };
+
+    let mut medias: HashMap<String, MessageMedia> = HashMap::new();
+    let mut placeholders: Vec<String> = vec![];
+    for (idx, image) in images
+        .get("data")
+        .and_then(|data| data.as_array())
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        let url = if let Some(url) = image.get("url").and_then(|url| url.as_str()) {
+            url.to_string()
+        } else if let Some(b64) = image.get("b64_json").and_then(|b64| b64.as_str()) {
+            format!("data:image/png;base64,{b64}")
+        } else {
+            continue;
+        };
+        let name = format!("image_{idx}");
+        placeholders.push(format!("${{{{{name}}}}}"));
+        medias.insert(
+            name,
+            MessageMedia {
+                ty: MediaType::Image,
+                url,
+            },
+        );
+    }
+
+    let produced = medias.len() as i32;
+    let mut reply = Message::new(
+        chat_id,
+        MessageModel::Others,
+        MessageSender::Assistant,
+        placeholders.join("\n"),
+        Some(medias),
+    );
+    // Only upstream URLs in there, nothing already stored to check.
+    media.store_medias(&mut reply, &HashSet::new()).await;
+    let reply = match chat.branch(&store, prompt_msg.msg_id, reply).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            log::error!(target: "app", "Unable to save images to chat `{chat_id}`: {e}");
+            return with_message_headers(
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save images"),
+                chat_id,
+                prompt_msg.msg_id.unwrap(),
+            );
+        }
+    };
+    log::debug!(target: "app", "Assistant message ID: `{}` of chat ID `{}` saved to database", reply.msg_id.unwrap(), chat_id);
+
+    charge(&store, &user, QuotaType::ImageGeneration, produced).await;
+
+    let resp = with_message_headers(
+        resp_builder.body(bytes),
+        chat_id,
+        prompt_msg.msg_id.unwrap(),
+    );
+    with_reply_header(resp, reply.msg_id.unwrap())
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, VecDeque};
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+
+use crate::{
+    handlers::current_user,
+    models::{Chat, Message, MessageModel, MessageSender, Store, User},
+    types::{
+        chatgpt::{Conversation, NodeMessage},
+        error::{error_response, ApiError},
+    },
+    utils::reload::LiveConfig,
+};
+
+/// Last second a MySQL `DATETIME` holds, 9999-12-31 23:59:59.
+const MAX_TIMESTAMP: f64 = 253_402_300_799.0;
+
+/// Time given in seconds since epoch, as used by ChatGPT. `None` for times
+/// before the epoch or past what the database can hold.
+fn timestamp(secs: f64) -> Option<DateTime<Utc>> {
+    if !(0.0..=MAX_TIMESTAMP).contains(&secs) {
+        return None;
+    }
+    Utc.timestamp_opt(secs.trunc() as i64, (secs.fract() * 1e9) as u32)
+        .single()
+}
+
+/// Model of a ChatGPT model slug.
+fn model_of_slug(slug: &str) -> MessageModel {
+    // ChatGPT served GPT-3.5 under its own slug.
+    if slug.starts_with("text-davinci-002-render") {
+        return MessageModel::GPT_3_5_Turbo;
+    }
+    slug.into()
+}
+
+/// Message of a conversation node, unless there is nothing worth keeping.
+fn message_of(chat_id: i32, node: &NodeMessage) -> Option<Message> {
+    let sender = match node.author.role.as_str() {
+        "user" => MessageSender::User,
+        "assistant" => MessageSender::Assistant,
+        "system" => MessageSender::System,
+        // Tool calls and their results stay out.
+        _ => return None,
+    };
+    let content = node.content.text();
+    if content.trim().is_empty() {
+        return None;
+    }
+
+    let model = node
+        .metadata
+        .model_slug
+        .as_deref()
+        .map(model_of_slug)
+        .unwrap_or(MessageModel::Others);
+    let mut message = Message::new(chat_id, model, sender, content, None);
+    if let Some(created_at) = node.create_time.and_then(timestamp) {
+        message.msg_created_at = created_at;
+    }
+    Some(message)
+}
+
+/// Empty chat of `user` a conversation is imported into.
+fn chat_of(user: &User, conversation: &Conversation) -> Chat {
+    let mut chat = Chat::new(user.id().unwrap());
+    if let Some(created_at) = conversation.create_time.and_then(timestamp) {
+        chat.chat_created_at = created_at;
+        chat.chat_date = created_at.date_naive();
+    }
+    chat.chat_summary = conversation.title.clone();
+    chat
+}
+
+/// Save the messages of one conversation into `chat`, keeping its branches.
+/// Returns how many were saved.
+async fn import_conversation(
+    store: &Store,
+    chat: &mut Chat,
+    conversation: &Conversation,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let chat_id = chat.chat_id.unwrap();
+
+    // Walk the tree from its roots, so parents are saved before replies.
+    // Skipped nodes hand their parent down to their children.
+    let mut saved: HashMap<&str, Option<i32>> = HashMap::new();
+    let mut queue: VecDeque<(&str, Option<i32>)> = conversation
+        .mapping
+        .iter()
+        .filter(|(_, node)| {
+            node.parent
+                .as_ref()
+                .is_none_or(|parent| !conversation.mapping.contains_key(parent))
+        })
+        .map(|(id, _)| (id.as_str(), None))
+        .collect();
+    let mut count = 0;
+    while let Some((id, parent_id)) = queue.pop_front() {
+        // A crafted export may list a node as a child of itself or of its
+        // descendants, each node is saved once.
+        if saved.contains_key(id) {
+            continue;
+        }
+        let node = &conversation.mapping[id];
+        let msg_id = match node
+            .message
+            .as_ref()
+            .and_then(|msg| message_of(chat_id, msg))
+        {
+            Some(mut message) => {
+                message.msg_parent_id = parent_id;
+                count += 1;
+                store.messages.save(&message).await?.msg_id
+            }
+            None => parent_id,
+        };
+        saved.insert(id, msg_id);
+        queue.extend(
+            node.children
+                .iter()
+                .filter(|child| conversation.mapping.contains_key(*child))
+                .filter(|child| !saved.contains_key(child.as_str()))
+                .map(|child| (child.as_str(), msg_id)),
+        );
+    }
+
+    if let Some(Some(leaf)) = conversation
+        .current_node
+        .as_deref()
+        .and_then(|node| saved.get(node))
+    {
+        store.chats.set_leaf(chat, *leaf).await?;
+    }
+    Ok(count)
+}
+
+/// Read the whole request body, as long as it fits in `limit` bytes.
+async fn read_body(mut payload: web::Payload, limit: usize) -> Result<web::BytesMut, HttpResponse> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk
+            .map_err(|e| error_response(StatusCode::BAD_REQUEST, format!("Invalid upload: {e}")))?;
+        if body.len() + chunk.len() > limit {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Export exceeds {limit} bytes"),
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Import chats from the `conversations.json` of a ChatGPT data export.
+///
+/// Chats are saved one after the other. On failure, the error lists the chats
+/// imported already, and the chat left incomplete if any, so they can be
+/// told apart from a retry.
+pub async fn chatgpt(
+    req: HttpRequest,
+    payload: web::Payload,
+    config: web::Data<LiveConfig>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let body = match read_body(payload, config.get().limits.import_payload).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+    let conversations: Vec<Conversation> = match serde_json::from_slice(&body) {
+        Ok(conversations) => conversations,
+        Err(e) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid ChatGPT export: {e}"),
+            )
+        }
+    };
+
+    let mut imported = vec![];
+    for conversation in conversations.iter() {
+        let mut chat = None;
+        let result = match store.chats.save(&chat_of(&user, conversation)).await {
+            Ok(saved) => import_conversation(&store, chat.insert(saved), conversation).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(count) => {
+                let chat = chat.unwrap();
+                imported.push(serde_json::json!({
+                    "chat_id": chat.chat_id,
+                    "title": chat.chat_summary,
+                    "messages": count,
+                }))
+            }
+            Err(e) => {
+                log::error!(target: "app", "Unable to import chats of user `{}`: {e}", user.name());
+                let error = ApiError::new(
+                    "server_error",
+                    format!(
+                        "Unable to import chats, {} imported before failing",
+                        imported.len()
+                    ),
+                );
+                let mut body = serde_json::to_value(error).unwrap();
+                body["chats"] = serde_json::json!(imported);
+                body["incomplete_chat_id"] = serde_json::json!(chat.and_then(|chat| chat.chat_id));
+                return HttpResponse::InternalServerError().json(body);
+            }
+        }
+    }
+    log::info!(target: "app", "{} chats imported for user `{}`", imported.len(), user.name());
+    HttpResponse::Ok().json(serde_json::json!({ "chats": imported }))
+}
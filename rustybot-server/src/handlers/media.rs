@@ -0,0 +1,66 @@
+use actix_web::{
+    http::{
+        header::{CacheControl, CacheDirective, CONTENT_SECURITY_POLICY, X_CONTENT_TYPE_OPTIONS},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+
+use crate::{
+    handlers::current_user, media::MediaService, models::Store, types::error::error_response,
+};
+
+/// Serve a stored media by its content hash, to users with a message it is
+/// attached to. Others are told it does not exist.
+///
+/// Content never changes for a given hash, so it may be cached forever.
+pub async fn serve(
+    req: HttpRequest,
+    hash: web::Path<String>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let owned = match store.messages.has_media(user.id().unwrap(), &hash).await {
+        Ok(owned) => owned,
+        Err(e) => {
+            log::error!(target: "app", "Unable to query owner of media `{hash}`: {e}");
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read media");
+        }
+    };
+    if !owned {
+        return error_response(StatusCode::NOT_FOUND, format!("Media `{hash}` not found"));
+    }
+
+    match media.get(&hash).await {
+        Ok(Some((body, content_type))) => media_response(
+            body,
+            &content_type,
+            CacheControl(vec![
+                CacheDirective::Private,
+                CacheDirective::MaxAge(31_536_000),
+                CacheDirective::Extension("immutable".to_string(), None),
+            ]),
+        ),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, format!("Media `{hash}` not found")),
+        Err(e) => {
+            log::error!(target: "app", "Unable to read media `{hash}`: {e}");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read media")
+        }
+    }
+}
+
+/// Response serving a media. Browsers are kept from guessing another content
+/// type, and from running anything should the media be opened as a page.
+pub fn media_response(body: Bytes, content_type: &str, cache: CacheControl) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(cache)
+        .insert_header((X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((CONTENT_SECURITY_POLICY, "sandbox"))
+        .body(body)
+}
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use actix_web::{
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    HttpMessage, HttpRequest, HttpResponse,
+};
+
+use crate::{
+    media::MediaService,
+    middleware::RequestModel,
+    models::{Chat, Quota, QuotaType, Store, User},
+    types::error::{error_response, ApiError},
+    utils::config::Config,
+};
+
+pub mod audio;
+pub mod chat;
+pub mod export;
+pub mod health;
+pub mod image;
+pub mod import;
+pub mod media;
+pub mod proxy;
+pub mod search;
+pub mod share;
+
+/// Look up the user behind a request that went through authentication.
+pub async fn current_user(req: &HttpRequest, store: &Store) -> Result<User, HttpResponse> {
+    let name = req
+        .headers()
+        .get("x-rustybot-id")
+        .and_then(|name| name.to_str().ok())
+        .ok_or_else(|| {
+            error_response(StatusCode::UNAUTHORIZED, "Missing `x-rustybot-id` header")
+        })?;
+
+    match store.users.find_by_name(name).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            format!("User `{name}` not found"),
+        )),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query user `{name}`: {e}");
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to query user",
+            ))
+        }
+    }
+}
+
+/// Common checks before a request is proxied upstream: the caller must be
+/// allowed to use `model` and have at least `amount` units of `quota` left.
+pub async fn admit(
+    req: &HttpRequest,
+    store: &Store,
+    config: &Config,
+    model: &str,
+    quota: QuotaType,
+    amount: i32,
+) -> Result<User, HttpResponse> {
+    let user = current_user(req, store).await?;
+    req.extensions_mut().insert(RequestModel(model.to_string()));
+
+    log::info!(
+        target: "app",
+        "User `{}` requested `{}` with model `{}`",
+        user.name(),
+        req.path(),
+        model
+    );
+
+    if !config.models.allows(&user.role(), model) {
+        log::warn!(target: "app", "User `{}` is not allowed to use model `{}`", user.name(), model);
+        return Err(ApiError::new(
+            "invalid_request_error",
+            format!("You are not allowed to use model `{model}`"),
+        )
+        .with_code("model_not_allowed")
+        .response(StatusCode::FORBIDDEN));
+    }
+
+    match Quota::check(store, user.id().unwrap(), quota, amount).await {
+        Ok(true) => Ok(user),
+        Ok(false) => {
+            log::warn!(target: "app", "User `{}` ran out of quota", user.name());
+            Err(
+                ApiError::new("insufficient_quota", "You exceeded your current quota")
+                    .with_code("insufficient_quota")
+                    .response(StatusCode::TOO_MANY_REQUESTS),
+            )
+        }
+        Err(e) => {
+            log::error!(target: "app", "Unable to query quota of user `{}`: {e}", user.name());
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to query quota",
+            ))
+        }
+    }
+}
+
+/// Record quota usage once the upstream request went through.
+pub async fn charge(store: &Store, user: &User, quota: QuotaType, amount: i32) {
+    if let Err(e) = store
+        .quotas
+        .consume(user.id().unwrap(), quota, amount)
+        .await
+    {
+        log::error!(target: "app", "Unable to charge quota of user `{}`: {e}", user.name());
+    }
+}
+
+/// Chat given by the `x-rustybot-chat-id` header, which must belong to `user`.
+pub async fn requested_chat(
+    req: &HttpRequest,
+    store: &Store,
+    user: &User,
+) -> Result<Chat, HttpResponse> {
+    let chat_id = req
+        .headers()
+        .get("x-rustybot-chat-id")
+        .and_then(|chat_id| chat_id.to_str().ok())
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "Missing `x-rustybot-chat-id` header",
+            )
+        })?
+        .parse::<i32>()
+        .map_err(|_| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "Invalid `x-rustybot-chat-id` header",
+            )
+        })?;
+    owned_chat(store, user, chat_id).await
+}
+
+/// Chat `chat_id`, only when it belongs to `user`.
+pub async fn owned_chat(store: &Store, user: &User, chat_id: i32) -> Result<Chat, HttpResponse> {
+    match store.chats.find_by_id(chat_id).await {
+        Ok(Some(chat)) if Some(chat.chat_user_id) == user.id() => Ok(chat),
+        Ok(_) => Err(error_response(
+            StatusCode::NOT_FOUND,
+            format!("Chat `{chat_id}` not found"),
+        )),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query chat `{chat_id}`: {e}");
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to query chat",
+            ))
+        }
+    }
+}
+
+/// Hashes of the stored medias behind `urls` that user `uid` may read: those
+/// attached to messages of its own chats.
+pub async fn owned_medias<'a>(
+    store: &Store,
+    media: &MediaService,
+    uid: i32,
+    urls: impl IntoIterator<Item = &'a str>,
+) -> HashSet<String> {
+    let mut owned = HashSet::new();
+    for url in urls {
+        let Some(hash) = media.hash_of(url) else {
+            continue;
+        };
+        match store.messages.has_media(uid, hash).await {
+            Ok(true) => {
+                owned.insert(hash.to_string());
+            }
+            Ok(false) => {}
+            Err(e) => log::error!(target: "app", "Unable to query owner of media `{hash}`: {e}"),
+        }
+    }
+    owned
+}
+
+/// Tell the client where the prompt has been stored.
+pub fn with_message_headers(mut resp: HttpResponse, chat_id: i32, msg_id: i32) -> HttpResponse {
+    let headers = resp.headers_mut();
+    headers.insert(
+        HeaderName::from_static("x-rustybot-chat-id"),
+        HeaderValue::from(chat_id),
+    );
+    headers.insert(
+        HeaderName::from_static("x-rustybot-message-id"),
+        HeaderValue::from(msg_id),
+    );
+    resp
+}
+
+/// Tell the client where the reply to its prompt has been stored.
+pub fn with_reply_header(mut resp: HttpResponse, msg_id: i32) -> HttpResponse {
+    resp.headers_mut().insert(
+        HeaderName::from_static("x-rustybot-reply-id"),
+        HeaderValue::from(msg_id),
+    );
+    resp
+}
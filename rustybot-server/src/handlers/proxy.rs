@@ -0,0 +1,177 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use reqwest::Method;
+use tokio::sync::mpsc::{channel, Sender};
+
+use crate::{
+    handlers::{admit, charge, current_user},
+    metrics,
+    middleware::TokenUsage,
+    models::{QuotaType, Store},
+    request::RemoteClient,
+    types::error::error_response,
+    utils::{reload::LiveConfig, tasks},
+};
+
+/// Model name of a request body passed through as-is.
+fn requested_model(data: &serde_json::Value) -> Result<String, HttpResponse> {
+    data.get("model")
+        .and_then(|model| model.as_str())
+        .map(|model| model.to_string())
+        .ok_or_else(|| error_response(StatusCode::BAD_REQUEST, "Missing `model` in request body"))
+}
+
+/// Pass a request through, counting the tokens of the `usage` upstream
+/// answers with like chat completions do.
+async fn post_counted(
+    remote: &RemoteClient,
+    endpoint: &str,
+    data: &serde_json::Value,
+    model: &str,
+    user_id: i32,
+) -> HttpResponse {
+    let (mut resp_builder, bytes) = match remote
+        .call(Method::POST, endpoint, |req| req.json(data))
+        .await
+    {
+        Ok(result) => result,
+        Err(resp) => return resp,
+    };
+    let usage =
+        serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default()["usage"].take();
+    metrics::record_usage(model, user_id, &usage);
+    let mut resp = resp_builder.body(bytes);
+    resp.extensions_mut().insert(TokenUsage::of(&usage));
+    resp
+}
+
+/// Sender of a relayed stream counting the tokens of the `usage` its last
+/// event carries, sent with `stream_options.include_usage`.
+fn stream_counter(model: String, user_id: i32) -> Sender<Bytes> {
+    let (sender, mut receiver) = channel::<Bytes>(1024);
+    tasks::spawn(async move {
+        // Events may be cut anywhere, so bytes are kept until their line is
+        // complete.
+        let mut pending: Vec<u8> = Vec::new();
+        while let Some(bytes) = receiver.recv().await {
+            if bytes.as_ref() == b"EOS__EOS" {
+                break;
+            }
+            pending.extend_from_slice(&bytes);
+            let Some(end) = pending.iter().rposition(|byte| *byte == b'\n') else {
+                continue;
+            };
+            let lines: Vec<u8> = pending.drain(..=end).collect();
+            for line in String::from_utf8_lossy(&lines).lines() {
+                if let Some(event) = line.trim().strip_prefix("data: ") {
+                    if let Ok(event) = serde_json::from_str::<serde_json::Value>(event) {
+                        metrics::record_usage(&model, user_id, &event["usage"]);
+                    }
+                }
+            }
+        }
+    });
+    sender
+}
+
+/// Proxy `/v1/embeddings`.
+pub async fn embeddings(
+    req: HttpRequest,
+    data: web::Json<serde_json::Value>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let model = match requested_model(&data) {
+        Ok(model) => model,
+        Err(resp) => return resp,
+    };
+    let user = match admit(&req, &store, &config, &model, QuotaType::ChatCompletion, 1).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    let resp = post_counted(&remote, "/v1/embeddings", &data, &model, user.id().unwrap()).await;
+    if resp.status().is_success() {
+        charge(&store, &user, QuotaType::ChatCompletion, 1).await;
+    }
+    resp
+}
+
+/// Proxy the legacy `/v1/completions`, streaming or not.
+pub async fn completions(
+    req: HttpRequest,
+    data: web::Json<serde_json::Value>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let endpoint = "/v1/completions";
+    let model = match requested_model(&data) {
+        Ok(model) => model,
+        Err(resp) => return resp,
+    };
+    let user = match admit(&req, &store, &config, &model, QuotaType::ChatCompletion, 1).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    let stream = data
+        .get("stream")
+        .and_then(|stream| stream.as_bool())
+        .unwrap_or(false);
+    let user_id = user.id().unwrap();
+    let resp = if stream {
+        let sender = stream_counter(model, user_id);
+        remote
+            .post_remote_stream(endpoint, &data, Some(sender))
+            .await
+    } else {
+        post_counted(&remote, endpoint, &data, &model, user_id).await
+    };
+    if resp.status().is_success() {
+        charge(&store, &user, QuotaType::ChatCompletion, 1).await;
+    }
+    resp
+}
+
+/// Proxy `/v1/models`, only listing models the caller may use.
+pub async fn models(
+    req: HttpRequest,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    let (mut resp_builder, bytes) = match remote.call(Method::GET, "/v1/models", |req| req).await {
+        Ok(result) => result,
+        Err(resp) => return resp,
+    };
+    let mut models: serde_json::Value = match serde_json::from_slice(&bytes) {
+        Ok(models) => models,
+        Err(e) => {
+            log::error!(target: "openai", "Unable to parse model list: {e}");
+            return error_response(
+                StatusCode::BAD_GATEWAY,
+                "Unable to parse upstream model list",
+            );
+        }
+    };
+
+    if let Some(data) = models.get_mut("data").and_then(|data| data.as_array_mut()) {
+        data.retain(|model| {
+            model
+                .get("id")
+                .and_then(|id| id.as_str())
+                .is_some_and(|id| config.models.allows(&user.role(), id))
+        });
+    }
+    resp_builder.json(models)
+}
@@ -0,0 +1,179 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use chrono::NaiveDate;
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    handlers::current_user,
+    models::{MessageSearch, MessageSender, Store},
+    types::error::error_response,
+};
+
+/// Characters of context kept around the first match in snippets.
+const SNIPPET_CONTEXT: usize = 80;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+
+    /// Model name, e.g. `gpt-4`.
+    pub model: Option<String>,
+
+    /// `user`, `assistant` or `system`.
+    pub sender: Option<String>,
+
+    pub from: Option<NaiveDate>,
+
+    pub to: Option<NaiveDate>,
+
+    #[serde(default = "SearchQuery::default_limit")]
+    pub limit: u32,
+
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl SearchQuery {
+    fn default_limit() -> u32 {
+        20
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SearchHit {
+    pub chat_id: i32,
+    pub msg_id: i32,
+    pub sender: MessageSender,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+
+    /// HTML excerpt around the first match, matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+/// Pattern matching any word of the query, case-insensitively.
+fn terms_pattern(query: &str) -> Option<Regex> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| term.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|term| !term.is_empty())
+        .map(regex::escape)
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    RegexBuilder::new(&terms.join("|"))
+        .case_insensitive(true)
+        .build()
+        .ok()
+}
+
+/// Excerpt of `content` around the first match of `pattern`, with matches
+/// highlighted.
+fn snippet(content: &str, pattern: Option<&Regex>) -> String {
+    let first = pattern
+        .and_then(|pattern| pattern.find(content))
+        .map(|found| found.start())
+        .unwrap_or(0);
+
+    let chars: Vec<(usize, char)> = content.char_indices().collect();
+    let at = chars.partition_point(|(idx, _)| *idx < first);
+    let start = at.saturating_sub(SNIPPET_CONTEXT);
+    let end = (at + 2 * SNIPPET_CONTEXT).min(chars.len());
+    let byte_start = chars.get(start).map_or(content.len(), |(idx, _)| *idx);
+    let byte_end = chars.get(end).map_or(content.len(), |(idx, _)| *idx);
+
+    // Message text is escaped, only `<mark>` is markup.
+    let excerpt = &content[byte_start..byte_end];
+    let mut highlighted = String::new();
+    let mut last = 0;
+    for found in pattern
+        .iter()
+        .flat_map(|pattern| pattern.find_iter(excerpt))
+    {
+        highlighted.push_str(&escape_html(&excerpt[last..found.start()]));
+        highlighted.push_str(&format!("<mark>{}</mark>", escape_html(found.as_str())));
+        last = found.end();
+    }
+    highlighted.push_str(&escape_html(&excerpt[last..]));
+    format!(
+        "{}{}{}",
+        if byte_start > 0 { "…" } else { "" },
+        highlighted,
+        if byte_end < content.len() { "…" } else { "" }
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Search the caller's messages with `/v1/search?q=`.
+pub async fn messages(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let query = query.into_inner();
+    if query.q.trim().is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "Missing search words in `q`");
+    }
+    let sender = match query.sender.as_deref() {
+        None => None,
+        Some("user") => Some(MessageSender::User),
+        Some("assistant") => Some(MessageSender::Assistant),
+        Some("system") => Some(MessageSender::System),
+        Some(sender) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown sender `{sender}`"),
+            )
+        }
+    };
+
+    let search = MessageSearch {
+        query: query.q.clone(),
+        model: query.model.as_deref().map(|model| model.into()),
+        sender,
+        from: query.from,
+        to: query.to,
+        limit: query.limit.clamp(1, 100),
+        offset: query.offset,
+    };
+    let messages = match store.messages.search(user.id().unwrap(), &search).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            log::error!(target: "app", "Unable to search messages of user `{}`: {e}", user.name());
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to search messages",
+            );
+        }
+    };
+
+    let pattern = terms_pattern(&query.q);
+    let hits: Vec<SearchHit> = messages
+        .into_iter()
+        .map(|message| SearchHit {
+            chat_id: message.msg_chat_id,
+            msg_id: message.msg_id.unwrap(),
+            snippet: snippet(&message.msg_content, pattern.as_ref()),
+            sender: message.msg_sender,
+            created_at: message.msg_created_at,
+        })
+        .collect();
+    HttpResponse::Ok().json(hits)
+}
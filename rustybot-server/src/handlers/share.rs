@@ -0,0 +1,258 @@
+use actix_web::{
+    http::{
+        header::{CacheControl, CacheDirective},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use chrono::{Datelike, Duration, Utc};
+
+use crate::{
+    handlers::{current_user, media::media_response, owned_chat},
+    media::MediaService,
+    models::{Chat, Message, Share, Store},
+    types::error::error_response,
+};
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct ShareRequest {
+    /// Seconds the link stays valid, forever when missing.
+    pub expires_in: Option<i64>,
+}
+
+/// Share as shown to its owner.
+fn describe(share: &Share) -> serde_json::Value {
+    serde_json::json!({
+        "token": share.share_token,
+        "url": format!("/share/{}", share.share_token),
+        "chat_id": share.share_chat_id,
+        "msg_id": share.share_leaf_msg_id,
+        "created_at": share.share_created_at,
+        "expires_at": share.share_expires_at,
+        "revoked": share.share_revoked,
+    })
+}
+
+/// Publish the selected branch of a chat, as it is now, behind a public link.
+pub async fn create(
+    req: HttpRequest,
+    chat_id: web::Path<i32>,
+    data: Option<web::Json<ShareRequest>>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let chat = match owned_chat(&store, &user, *chat_id).await {
+        Ok(chat) => chat,
+        Err(resp) => return resp,
+    };
+    let Some(leaf) = chat.leaf(&store).await else {
+        return error_response(StatusCode::BAD_REQUEST, "Nothing to share in an empty chat");
+    };
+
+    let expires_at = match data.and_then(|data| data.expires_in) {
+        Some(secs) if secs <= 0 => {
+            return error_response(StatusCode::BAD_REQUEST, "`expires_in` must be positive")
+        }
+        Some(secs) => {
+            // Refused past year 9999, the last one a MySQL `DATETIME` holds.
+            match secs
+                .checked_mul(1000)
+                .and_then(|ms| Utc::now().checked_add_signed(Duration::milliseconds(ms)))
+                .filter(|expires_at| expires_at.year() <= 9999)
+            {
+                Some(expires_at) => Some(expires_at),
+                None => {
+                    return error_response(StatusCode::BAD_REQUEST, "`expires_in` is too large")
+                }
+            }
+        }
+        None => None,
+    };
+    match store
+        .shares
+        .save(&Share::new(*chat_id, leaf, expires_at))
+        .await
+    {
+        Ok(share) => HttpResponse::Created().json(describe(&share)),
+        Err(e) => {
+            log::error!(target: "app", "Unable to share chat `{}`: {e}", *chat_id);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to share chat")
+        }
+    }
+}
+
+/// Every share of a chat, revoked and expired ones included.
+pub async fn list(
+    req: HttpRequest,
+    chat_id: web::Path<i32>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = owned_chat(&store, &user, *chat_id).await {
+        return resp;
+    }
+    match store.shares.find_by_chat(*chat_id).await {
+        Ok(shares) => HttpResponse::Ok().json(shares.iter().map(describe).collect::<Vec<_>>()),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query shares of chat `{}`: {e}", *chat_id);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to query shares")
+        }
+    }
+}
+
+/// Revoke a share, its link stops working for good.
+pub async fn revoke(
+    req: HttpRequest,
+    token: web::Path<String>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let not_found = || error_response(StatusCode::NOT_FOUND, format!("Share `{token}` not found"));
+    let mut share = match store.shares.find_by_token(&token).await {
+        Ok(Some(share)) => share,
+        Ok(None) => return not_found(),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query share: {e}");
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to query share");
+        }
+    };
+    if owned_chat(&store, &user, share.share_chat_id)
+        .await
+        .is_err()
+    {
+        return not_found();
+    }
+
+    match store.shares.revoke(&mut share).await {
+        Ok(()) => HttpResponse::Ok().json(describe(&share)),
+        Err(e) => {
+            log::error!(target: "app", "Unable to revoke share of chat `{}`: {e}", share.share_chat_id);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to revoke share")
+        }
+    }
+}
+
+/// Share behind `token`, unless revoked or expired.
+async fn active_share(store: &Store, token: &str) -> Result<(Share, Chat), HttpResponse> {
+    let not_found = || error_response(StatusCode::NOT_FOUND, "Shared chat not found");
+    let share = match store.shares.find_by_token(token).await {
+        Ok(Some(share)) if share.is_active() => share,
+        Ok(_) => return Err(not_found()),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query share: {e}");
+            return Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to query share",
+            ));
+        }
+    };
+    match store.chats.find_by_id(share.share_chat_id).await {
+        Ok(Some(chat)) => Ok((share, chat)),
+        Ok(None) => Err(not_found()),
+        Err(e) => {
+            log::error!(target: "app", "Unable to query chat `{}`: {e}", share.share_chat_id);
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to query chat",
+            ))
+        }
+    }
+}
+
+/// Messages of a share, stored medias pointing to the share's own media
+/// route as viewers are not authenticated.
+async fn shared_messages(
+    store: &Store,
+    share: &Share,
+    chat: &Chat,
+    media: &MediaService,
+) -> Vec<Message> {
+    let mut messages = chat.history_to(store, share.share_leaf_msg_id).await;
+    for message in messages.iter_mut() {
+        for (_, medium) in message
+            .msg_medias
+            .iter_mut()
+            .flat_map(|medias| medias.iter_mut())
+        {
+            if let Some(hash) = media.hash_of(&medium.url) {
+                medium.url = media.shared_url(&share.share_token, hash);
+            }
+        }
+    }
+    messages
+}
+
+/// Public read-only view of a shared chat at `/share/{token}`.
+pub async fn view(
+    token: web::Path<String>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let (share, chat) = match active_share(&store, &token).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+
+    let messages: Vec<serde_json::Value> = shared_messages(&store, &share, &chat, &media)
+        .await
+        .iter()
+        .map(|message| {
+            serde_json::json!({
+                "role": message.msg_sender.role(),
+                "content": message.message(),
+                "created_at": message.msg_created_at,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({
+        "title": chat.chat_summary,
+        "created_at": chat.chat_created_at,
+        "shared_at": share.share_created_at,
+        "messages": messages,
+    }))
+}
+
+/// Medias of a shared chat, only those part of the shared messages. Served
+/// like `/media/{hash}`, only images and audio and sandboxed.
+pub async fn media(
+    path: web::Path<(String, String)>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let (token, hash) = path.into_inner();
+    let (share, chat) = match active_share(&store, &token).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+    let shared = shared_messages(&store, &share, &chat, &media)
+        .await
+        .iter()
+        .flat_map(|message| message.msg_medias.iter().flat_map(|medias| medias.values()))
+        .any(|medium| medium.url == media.shared_url(&token, &hash));
+    if !shared {
+        return error_response(StatusCode::NOT_FOUND, format!("Media `{hash}` not found"));
+    }
+
+    match media.get(&hash).await {
+        // Not for shared caches, which would keep serving it once revoked.
+        Ok(Some((body, content_type))) => media_response(
+            body,
+            &content_type,
+            CacheControl(vec![CacheDirective::Private, CacheDirective::MaxAge(3600)]),
+        ),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, format!("Media `{hash}` not found")),
+        Err(e) => {
+            log::error!(target: "app", "Unable to read media `{hash}`: {e}");
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read media")
+        }
+    }
+}
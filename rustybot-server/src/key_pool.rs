@@ -0,0 +1,395 @@
+use std::{
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::utils::config::UpstreamKey;
+
+/// Why a key was reported as failed by [`KeyPool::report()`].
+#[derive(Debug, Clone)]
+pub enum KeyOutcome {
+    Success,
+    /// Upstream answered 429 for this key. Optional `Retry-After`.
+    RateLimited(Option<Duration>),
+    /// Upstream reported `insufficient_quota` for this key.
+    QuotaExhausted,
+    /// Upstream rejected the key itself (401/403).
+    Rejected,
+    /// Upstream or network failure that is not the key's fault.
+    Failed(String),
+}
+
+/// Per-key health statistics, as exposed to administrators.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct KeyHealth {
+    /// Masked key, only the last four characters are kept.
+    pub key: String,
+    pub organization: Option<String>,
+    pub weight: u32,
+    pub requests: u64,
+    pub successes: u64,
+    pub rate_limited: u64,
+    pub quota_exhausted: u64,
+    pub rejected: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Seconds left before the key is back in rotation.
+    pub cooldown_remaining: Option<u64>,
+}
+
+struct KeyState {
+    key: UpstreamKey,
+    current_weight: i64,
+    cooldown_until: Option<Instant>,
+    health: KeyHealth,
+}
+
+impl KeyState {
+    fn new(key: UpstreamKey) -> Self {
+        Self {
+            health: KeyHealth {
+                key: mask(&key.api_key),
+                organization: key.organization.clone(),
+                weight: key.weight,
+                ..Default::default()
+            },
+            key,
+            current_weight: 0,
+            cooldown_until: None,
+        }
+    }
+
+    fn available(&self, now: Instant) -> bool {
+        self.cooldown_until.is_none_or(|until| until <= now)
+    }
+}
+
+/// Weighted round-robin pool of upstream keys.
+///
+/// Keys are picked with the smooth weighted round-robin algorithm, keys that
+/// got rate limited or ran out of quota are put on cooldown and skipped until
+/// it expires.
+pub struct KeyPool {
+    keys: Mutex<Vec<KeyState>>,
+    /// Cooldown after a rate limit, then after exhaustion or rejection.
+    cooldowns: RwLock<(Duration, Duration)>,
+}
+
+impl KeyPool {
+    pub fn new(keys: Vec<UpstreamKey>, cooldown: Duration, exhausted_cooldown: Duration) -> Self {
+        Self {
+            keys: Mutex::new(keys.into_iter().map(KeyState::new).collect()),
+            cooldowns: RwLock::new((cooldown, exhausted_cooldown)),
+        }
+    }
+
+    /// Replace the keys and cooldowns. Keys still present keep their
+    /// statistics and cooldown, so a reload does not put a rate limited key
+    /// back in rotation.
+    pub fn reconfigure(
+        &self,
+        keys: Vec<UpstreamKey>,
+        cooldown: Duration,
+        exhausted_cooldown: Duration,
+    ) {
+        let mut current = self.keys.lock().unwrap();
+        let mut previous: Vec<KeyState> = current.drain(..).collect();
+        *current = keys
+            .into_iter()
+            .map(|key| {
+                let Some(idx) = previous
+                    .iter()
+                    .position(|state| state.key.api_key == key.api_key)
+                else {
+                    return KeyState::new(key);
+                };
+                let mut state = previous.swap_remove(idx);
+                state.health.organization = key.organization.clone();
+                state.health.weight = key.weight;
+                state.current_weight = 0;
+                state.key = key;
+                state
+            })
+            .collect();
+        *self.cooldowns.write().unwrap() = (cooldown, exhausted_cooldown);
+    }
+
+    /// Pick the next key, skipping the API keys in `exclude` (already tried
+    /// for the current request). Keys on cooldown are only used when nothing
+    /// else is left, the one recovering soonest first.
+    ///
+    /// Keys are told apart by their API key rather than their position, which
+    /// a reload may change while a request is in flight.
+    pub fn pick(&self, exclude: &[String]) -> Option<UpstreamKey> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+
+        let candidates: Vec<usize> = (0..keys.len())
+            .filter(|idx| !exclude.contains(&keys[*idx].key.api_key) && keys[*idx].key.weight > 0)
+            .collect();
+        let available: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|idx| keys[*idx].available(now))
+            .collect();
+
+        let idx = if available.is_empty() {
+            candidates
+                .into_iter()
+                .min_by_key(|idx| keys[*idx].cooldown_until)?
+        } else {
+            let total: i64 = available
+                .iter()
+                .map(|idx| keys[*idx].key.weight as i64)
+                .sum();
+            let mut best = available[0];
+            for idx in available.iter().copied() {
+                keys[idx].current_weight += keys[idx].key.weight as i64;
+                if keys[idx].current_weight > keys[best].current_weight {
+                    best = idx;
+                }
+            }
+            keys[best].current_weight -= total;
+            best
+        };
+
+        let state = &mut keys[idx];
+        state.health.requests += 1;
+        state.health.last_used_at = Some(Utc::now());
+        Some(state.key.clone())
+    }
+
+    /// Whether a key other than those in `exclude` is currently usable.
+    pub fn has_available(&self, exclude: &[String]) -> bool {
+        let keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        keys.iter().any(|state| {
+            !exclude.contains(&state.key.api_key) && state.key.weight > 0 && state.available(now)
+        })
+    }
+
+    /// Record the outcome of a request made with API key `api_key`. Keys
+    /// removed from the pool meanwhile are ignored.
+    pub fn report(&self, api_key: &str, outcome: KeyOutcome) {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(state) = keys.iter_mut().find(|state| state.key.api_key == api_key) else {
+            return;
+        };
+        let now = Instant::now();
+        let (cooldown, exhausted_cooldown) = *self.cooldowns.read().unwrap();
+        match outcome {
+            KeyOutcome::Success => {
+                state.health.successes += 1;
+                state.cooldown_until = None;
+            }
+            KeyOutcome::RateLimited(retry_after) => {
+                state.health.rate_limited += 1;
+                state.health.last_error = Some("rate limited".to_string());
+                state.cooldown_until = Some(now + retry_after.unwrap_or(cooldown));
+            }
+            KeyOutcome::QuotaExhausted => {
+                state.health.quota_exhausted += 1;
+                state.health.last_error = Some("quota exhausted".to_string());
+                state.cooldown_until = Some(now + exhausted_cooldown);
+            }
+            KeyOutcome::Rejected => {
+                state.health.rejected += 1;
+                state.health.last_error = Some("key rejected".to_string());
+                state.cooldown_until = Some(now + exhausted_cooldown);
+            }
+            KeyOutcome::Failed(e) => {
+                state.health.failures += 1;
+                state.health.last_error = Some(e);
+            }
+        }
+        if !state.available(now) {
+            log::warn!(
+                target: "openai",
+                "Upstream key `{}` put on cooldown: {}",
+                state.health.key,
+                state.health.last_error.clone().unwrap_or_default()
+            );
+        }
+    }
+
+    /// Snapshot of the health of every key in the pool.
+    pub fn health(&self) -> Vec<KeyHealth> {
+        let keys = self.keys.lock().unwrap();
+        let now = Instant::now();
+        keys.iter()
+            .map(|state| KeyHealth {
+                cooldown_remaining: state
+                    .cooldown_until
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs()),
+                ..state.health.clone()
+            })
+            .collect()
+    }
+}
+
+fn mask(key: &str) -> String {
+    let tail: String = key
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("...{tail}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(weights: &[u32]) -> KeyPool {
+        let keys = weights
+            .iter()
+            .enumerate()
+            .map(|(idx, weight)| UpstreamKey {
+                api_key: format!("sk-{idx}"),
+                organization: None,
+                weight: *weight,
+            })
+            .collect();
+        KeyPool::new(keys, Duration::from_secs(60), Duration::from_secs(3600))
+    }
+
+    /// Positions of the next `count` keys picked.
+    fn picks(pool: &KeyPool, count: usize) -> Vec<usize> {
+        (0..count)
+            .map(|_| idx(&pool.pick(&[]).unwrap().api_key))
+            .collect()
+    }
+
+    fn idx(api_key: &str) -> usize {
+        api_key.strip_prefix("sk-").unwrap().parse().unwrap()
+    }
+
+    fn keys(idx: &[usize]) -> Vec<String> {
+        idx.iter().map(|idx| format!("sk-{idx}")).collect()
+    }
+
+    #[test]
+    fn picks_follow_weights() {
+        let pool = pool(&[5, 1, 1]);
+        assert_eq!(picks(&pool, 7), [0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(picks(&pool, 7), [0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn zero_weight_is_never_picked() {
+        let pool = pool(&[0, 1]);
+        assert!(picks(&pool, 4).iter().all(|idx| *idx == 1));
+        assert!(pool.pick(&keys(&[1])).is_none());
+    }
+
+    #[test]
+    fn excluded_keys_are_skipped() {
+        let pool = pool(&[1, 1, 1]);
+        assert!((0..6).all(|_| pool.pick(&keys(&[0, 2])).unwrap().api_key == "sk-1"));
+        assert!(pool.has_available(&keys(&[0, 2])));
+        assert!(!pool.has_available(&keys(&[0, 1, 2])));
+    }
+
+    #[test]
+    fn cooled_down_keys_are_skipped() {
+        let pool = pool(&[1, 1]);
+        pool.report("sk-0", KeyOutcome::RateLimited(None));
+        assert!(picks(&pool, 4).iter().all(|idx| *idx == 1));
+        assert!(!pool.has_available(&keys(&[1])));
+        assert!(pool.health()[0].cooldown_remaining.is_some());
+
+        pool.report("sk-0", KeyOutcome::Success);
+        assert!(pool.has_available(&keys(&[1])));
+        assert!(pool.health()[0].cooldown_remaining.is_none());
+    }
+
+    #[test]
+    fn retry_after_sets_cooldown() {
+        let pool = pool(&[1, 1]);
+        pool.report("sk-0", KeyOutcome::RateLimited(Some(Duration::ZERO)));
+        assert!(pool.has_available(&keys(&[1])));
+
+        pool.report("sk-1", KeyOutcome::QuotaExhausted);
+        assert!(pool.health()[1].cooldown_remaining.unwrap() > 60);
+        assert!(!pool.has_available(&keys(&[0])));
+    }
+
+    #[test]
+    fn soonest_recovering_key_is_used_last() {
+        let pool = pool(&[1, 1]);
+        pool.report("sk-0", KeyOutcome::Rejected);
+        pool.report("sk-1", KeyOutcome::RateLimited(None));
+        assert_eq!(pool.pick(&[]).unwrap().api_key, "sk-1");
+        assert_eq!(pool.pick(&keys(&[1])).unwrap().api_key, "sk-0");
+    }
+
+    #[test]
+    fn failures_keep_key_in_rotation() {
+        let pool = pool(&[1]);
+        pool.report("sk-0", KeyOutcome::Failed("timed out".to_string()));
+        assert!(pool.has_available(&[]));
+        let health = &pool.health()[0];
+        assert_eq!(health.failures, 1);
+        assert_eq!(health.last_error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn reconfigure_keeps_cooldowns() {
+        let pool = pool(&[1, 1]);
+        pool.report("sk-0", KeyOutcome::RateLimited(None));
+        let keys = ["sk-new", "sk-0"]
+            .into_iter()
+            .map(|api_key| UpstreamKey {
+                api_key: api_key.to_string(),
+                organization: None,
+                weight: 1,
+            })
+            .collect();
+        pool.reconfigure(keys, Duration::from_secs(60), Duration::from_secs(3600));
+
+        let health = pool.health();
+        assert_eq!(health[0].key, "...-new");
+        assert_eq!(health[1].rate_limited, 1);
+        assert!(health[1].cooldown_remaining.is_some());
+        assert!((0..3).all(|_| pool.pick(&[]).unwrap().api_key == "sk-new"));
+    }
+
+    #[test]
+    fn reports_follow_keys_across_reloads() {
+        let pool = pool(&[1, 1]);
+        let picked = pool.pick(&[]).unwrap();
+        assert_eq!(picked.api_key, "sk-0");
+
+        // Reloaded in another order while the request is in flight.
+        let reordered = ["sk-1", "sk-0"]
+            .into_iter()
+            .map(|api_key| UpstreamKey {
+                api_key: api_key.to_string(),
+                organization: None,
+                weight: 1,
+            })
+            .collect();
+        pool.reconfigure(
+            reordered,
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        );
+        pool.report(&picked.api_key, KeyOutcome::QuotaExhausted);
+
+        let health = pool.health();
+        assert_eq!(health[0].quota_exhausted, 0);
+        assert_eq!(health[1].quota_exhausted, 1);
+        assert!(!pool.has_available(&keys(&[1])));
+
+        // Reports for keys no longer in the pool are dropped.
+        pool.report("sk-gone", KeyOutcome::Rejected);
+        assert!(pool.health().iter().all(|key| key.rejected == 0));
+    }
+}
@@ -1,130 +1,144 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use crate::{
-    request::{post_remote, post_remote_stream},
-    types::version::VersionInfo,
+    media::MediaService,
+    request::RemoteClient,
+    types::{chat::ChatCompletionRequest, error::error_response, version::VersionInfo},
+    utils::{config::Config, reload::LiveConfig, tls::CertResolver},
 };
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
-use bytes::Bytes;
-use middleware::AuthenticateMiddlewareFactory;
-use models::{Chat, Message, User};
-use rust_ai::openai::{types::chat_completion::Chunk, ChatCompletion};
-use tokio::sync::mpsc::channel;
+use actix_web::{http::StatusCode, web, App, HttpRequest, HttpResponse, HttpServer};
+use handlers::{
+    admit, audio, charge, chat, current_user, export, health, image, import,
+    media as media_handler, owned_medias, proxy, requested_chat, search, share,
+    with_message_headers,
+};
+use middleware::{
+    AuthenticateMiddlewareFactory, MetricsMiddlewareFactory, RequestLogMiddlewareFactory,
+};
+use models::{Chat, Message, QuotaType, Store, UserRole};
 
 pub mod auth;
+pub mod handlers;
+pub mod key_pool;
 pub mod libs;
+pub mod media;
+pub mod metrics;
 pub mod middleware;
 pub mod models;
 pub mod request;
 pub mod types;
 pub mod utils;
 
-pub use utils::DB_POOL;
-
-async fn assign_chat_id(req: HttpRequest) -> HttpResponse {
-    let user = User::find_by_name(
-        req.headers()
-            .get("x-rustybot-id")
-            .unwrap()
-            .to_str()
-            .unwrap(),
-    )
-    .await
-    .unwrap()
-    .unwrap();
+async fn assign_chat_id(req: HttpRequest, store: web::Data<Store>) -> HttpResponse {
+    let user = store
+        .users
+        .find_by_name(
+            req.headers()
+                .get("x-rustybot-id")
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
 
-    let chat = Chat::new(user.id().unwrap()).save().await.unwrap();
+    let chat = store
+        .chats
+        .save(&Chat::new(user.id().unwrap()))
+        .await
+        .unwrap();
     HttpResponse::Ok()
         .content_type("application/json")
         .body(format!("{{\"chat_id\": {}}}", chat.chat_id.unwrap()))
 }
 
-async fn completions(req: HttpRequest, data: web::Json<ChatCompletion>) -> HttpResponse {
-    let endpoint = "/v1/chat/completions";
+#[tracing::instrument(skip_all, fields(model = %data.model, chat_id))]
+async fn completions(
+    req: HttpRequest,
+    data: web::Json<ChatCompletionRequest>,
+    remote: web::Data<RemoteClient>,
+    config: web::Data<LiveConfig>,
+    media: web::Data<MediaService>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let config = config.get();
+    let mut data = data.into_inner();
 
-    let chat_id = if let Some(chat_id_header_val) = req.headers().get("x-rustybot-chat-id") {
-        chat_id_header_val.to_str().unwrap()
-    } else {
-        return HttpResponse::BadRequest().finish();
+    let Some(last_message) = data.messages.last() else {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing `messages` in request body",
+        );
+    };
+
+    let user = match admit(
+        &req,
+        &store,
+        &config,
+        &data.model,
+        QuotaType::ChatCompletion,
+        1,
+    )
+    .await
+    {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    let mut chat = match requested_chat(&req, &store, &user).await {
+        Ok(chat) => chat,
+        Err(resp) => return resp,
     };
 
     // Always save last message to given chat.
-    let chat_id = chat_id.parse().unwrap();
-    let current_model: models::MessageModel = data.model.clone().into();
-    let _new_prompt = Message::new(
+    let chat_id = chat.chat_id.unwrap();
+    tracing::Span::current().record("chat_id", chat_id);
+    let current_model: models::MessageModel = data.model.as_str().into();
+    let mut _new_prompt = Message::from_content(
         chat_id,
         current_model,
         models::MessageSender::User,
-        data.messages.last().unwrap().content.clone(),
-        None,
+        &last_message.content,
+    );
+    let urls = data.image_urls();
+    let owned = owned_medias(
+        &store,
+        &media,
+        chat.chat_user_id,
+        urls.iter().map(String::as_str),
     )
-    .save()
-    .await
-    .unwrap();
+    .await;
+    media.store_medias(&mut _new_prompt, &owned).await;
+    let _new_prompt = chat.append(&store, _new_prompt).await.unwrap();
     log::debug!(target: "app", "User message ID: `{}` of chat ID `{}` saved to database", _new_prompt.msg_id.unwrap(), chat_id);
 
-    if data.stream.is_none() || data.stream == Some(false) {
-        // No stream mode
-        post_remote(endpoint, &data, None).await
-    } else {
-        // Stream mode
-        let (sender, mut receiver) = channel::<Bytes>(1024);
-        tokio::spawn(async move {
-            let mut completion_message = String::new();
-            while let Some(bytes) = receiver.recv().await {
-                let chunk_data_raw = String::from_utf8(bytes.to_vec()).unwrap();
-
-                if chunk_data_raw == "EOS__EOS" {
-                    break;
-                }
-
-                log::debug!(
-                    target: "openai",
-                    "BYTES FROM STREAM AFTER MESSAGE ID `{}`: {}",
-                    _new_prompt.msg_id.unwrap(),
-                    chunk_data_raw
-                );
-
-                for chunk_data in chunk_data_raw.split("\n") {
-                    let chunk_data = chunk_data.trim().to_string();
-                    if &chunk_data == "data: [DONE]" {
-                        log::debug!(target: "openai", "Last chunk received.");
-                        break;
-                    }
-                    if chunk_data.starts_with("data: ") {
-                        // Strip response content:
-                        let stripped_chunk = &chunk_data.trim()[6..];
-                        if let Ok(message_chunk) = serde_json::from_str::<Chunk>(stripped_chunk) {
-                            completion_message.push_str(
-                                &message_chunk
-                                    .choices
-                                    .last()
-                                    .unwrap()
-                                    .delta
-                                    .content
-                                    .clone()
-                                    .unwrap_or(String::new()),
-                            );
-                        }
-                    }
-                }
-            }
-            // Save response to database.
-            let _new_prompt = Message::new(
-                chat_id,
-                current_model,
-                models::MessageSender::Assistant,
-                completion_message,
-                None,
-            )
-            .save()
-            .await
-            .unwrap();
-            log::debug!(target: "app", "Assistant message ID: `{}` of chat ID `{}` saved to database", _new_prompt.msg_id.unwrap(), chat_id);
-        });
+    let prompt_id = _new_prompt.msg_id.unwrap();
+    let vision = config.models.supports_vision(&data.model);
+    chat::prepare_messages(&mut data, vision, &media, &owned).await;
 
-        post_remote_stream(endpoint, &data, Some(sender)).await
+    let resp = chat::reply(&remote, &store, &data, chat, prompt_id).await;
+    if resp.status().is_success() {
+        charge(&store, &user, QuotaType::ChatCompletion, 1).await;
     }
+
+    with_message_headers(resp, chat_id, prompt_id)
+}
+
+/// Health of every upstream key, only available to administrators.
+async fn upstream_keys(
+    req: HttpRequest,
+    remote: web::Data<RemoteClient>,
+    store: web::Data<Store>,
+) -> HttpResponse {
+    let user = match current_user(&req, &store).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+
+    if !matches!(user.role(), UserRole::Admin) {
+        return error_response(StatusCode::FORBIDDEN, "Administrators only");
+    }
+    HttpResponse::Ok().json(remote.key_health())
 }
 
 async fn version_info() -> HttpResponse {
@@ -144,23 +158,119 @@ async fn verify_authentication() -> HttpResponse {
         .body(r#"{"result": "pass"}"#)
 }
 
-pub async fn create_server() -> std::io::Result<()> {
-    HttpServer::new(|| {
+/// Serve the API until the process is told to stop, with models kept in
+/// `store`.
+pub async fn create_server(config: Config, store: Store) -> std::io::Result<()> {
+    let bind = (config.server.host.clone(), config.server.port);
+    let workers = config.server.workers;
+    let shutdown_timeout = config.server.shutdown_timeout;
+    let tls = match config.server.tls.clone() {
+        Some(tls) => {
+            let resolver = Arc::new(
+                CertResolver::new(&tls).map_err(|e| std::io::Error::other(e.to_string()))?,
+            );
+            let server_config = utils::tls::server_config(&tls, resolver.clone())
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            resolver.watch(tls.clone());
+            Some((tls.port, server_config))
+        }
+        None => None,
+    };
+    let remote = web::Data::new(
+        RemoteClient::new(&config).map_err(|e| std::io::Error::other(e.to_string()))?,
+    );
+    let media = web::Data::new(MediaService::from_config(&config.media));
+    utils::tasks::init();
+    let json_payload = config.limits.json_payload;
+    let config = web::Data::new(LiveConfig::new(config));
+    let store = web::Data::new(store);
+    let app_store = store.clone();
+    utils::reload::watch(config.clone().into_inner(), remote.clone().into_inner());
+
+    let server = HttpServer::new(move || {
         App::new()
+            .wrap(MetricsMiddlewareFactory::new())
+            .wrap(RequestLogMiddlewareFactory::new())
+            .app_data(web::JsonConfig::default().limit(json_payload))
+            .app_data(remote.clone())
+            .app_data(config.clone())
+            .app_data(media.clone())
+            .app_data(app_store.clone())
             .service(web::scope("/info").route("/version", web::get().to(version_info)))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/metrics", web::get().to(health::metrics))
             .service(
                 web::scope("/v1")
                     .wrap(AuthenticateMiddlewareFactory::new())
                     .route("/chat/completions", web::post().to(completions))
-                    .route("/chat/new", web::post().to(assign_chat_id)),
+                    .route("/chat/new", web::post().to(assign_chat_id))
+                    .route("/chats/export", web::get().to(export::all))
+                    .route("/chats/import", web::post().to(import::chatgpt))
+                    .route("/chats/{chat_id}/export", web::get().to(export::chat))
+                    .route("/chats/{chat_id}/messages", web::get().to(chat::history))
+                    .route("/chats/{chat_id}/shares", web::post().to(share::create))
+                    .route("/chats/{chat_id}/shares", web::get().to(share::list))
+                    .route("/shares/{token}", web::delete().to(share::revoke))
+                    .route("/messages/{msg_id}/edit", web::post().to(chat::edit))
+                    .route(
+                        "/messages/{msg_id}/regenerate",
+                        web::post().to(chat::regenerate),
+                    )
+                    .route("/messages/{msg_id}/versions", web::get().to(chat::versions))
+                    .route("/messages/{msg_id}/select", web::post().to(chat::select))
+                    .route("/completions", web::post().to(proxy::completions))
+                    .route("/embeddings", web::post().to(proxy::embeddings))
+                    .route("/images/generations", web::post().to(image::generations))
+                    .route("/audio/speech", web::post().to(audio::speech))
+                    .route(
+                        "/audio/transcriptions",
+                        web::post().to(audio::transcriptions),
+                    )
+                    .route("/models", web::get().to(proxy::models))
+                    .route("/search", web::get().to(search::messages))
+                    .route("/upstream/keys", web::get().to(upstream_keys)),
             )
             .service(
                 web::scope("/auth")
                     .wrap(AuthenticateMiddlewareFactory::new())
                     .route("/verify", web::get().to(verify_authentication)),
             )
+            .service(
+                web::scope("/share")
+                    .route("/{token}", web::get().to(share::view))
+                    .route("/{token}/media/{hash}", web::get().to(share::media)),
+            )
+            .service(
+                web::scope("/media")
+                    .wrap(AuthenticateMiddlewareFactory::new())
+                    .route("/{hash}", web::get().to(media_handler::serve)),
+            )
     })
-    .bind(("0.0.0.0", 9090))?
-    .run()
-    .await
+    .on_connect(utils::tls::on_connect)
+    .shutdown_timeout(shutdown_timeout);
+    let server = if workers > 0 {
+        server.workers(workers)
+    } else {
+        server
+    };
+    let server = match tls {
+        Some((Some(port), tls)) => server
+            .bind(bind.clone())?
+            .bind_rustls((bind.0, port), tls)?,
+        Some((None, tls)) => server.bind_rustls(bind, tls)?,
+        None => server.bind(bind)?,
+    };
+    server.run().await?;
+
+    // Workers are gone, replies of the streams they were serving may still be
+    // on their way to the database.
+    let pending = utils::tasks::flush(Duration::from_secs(shutdown_timeout)).await;
+    if pending > 0 {
+        log::warn!(target: "app", "Shutting down with {pending} replies not saved");
+    }
+    // Close the pool once pending queries are done, so writes are not cut off
+    // when the process exits.
+    store.close().await;
+    Ok(())
 }
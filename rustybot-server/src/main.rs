@@ -1,31 +1,34 @@
 use rustybot_server::{
     create_server,
+    models::Store,
     // models::{Chat, Message, MessageSender},
-    utils::db::init_pool,
+    utils::{config::Config, db::create_pool, logging, telemetry},
 };
-use std::{error::Error, path::PathBuf};
+use std::error::Error;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    // Must call this to initialize database pool.
-    init_pool();
-
-    if PathBuf::from("log4rs.yml").exists() {
-        std::env::set_var("RUST_LOG", "debug");
-        std::env::set_var("RUST_BACKTRACE", "1");
-        log4rs::init_file("log4rs.yml", Default::default()).unwrap();
-    }
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    logging::init(&config.logging);
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
-    let _eg = rt.enter();
 
-    tokio::runtime::Runtime::new().unwrap().block_on(async {
-        create_server().await.unwrap();
+    rt.block_on(async {
+        if let Err(e) = telemetry::init(&config.tracing) {
+            log::error!(target: "app", "Unable to set up tracing: {e}");
+        }
+        let db = create_pool(&config.database).unwrap();
+        create_server(config, Store::mysql(db)).await.unwrap();
     });
-
-    rt.shutdown_background();
+    telemetry::shutdown();
 
     // tokio::runtime::Runtime::new().unwrap().block_on(async {
     // let mut user = User::find_by_name("admin").await.unwrap().unwrap();
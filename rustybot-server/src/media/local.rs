@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+
+use super::MediaStore;
+
+/// Media storage on the local filesystem.
+///
+/// Objects are spread over sub-directories named after the first two
+/// characters of their key, the content type is kept in a `.type` file next
+/// to each object.
+pub struct LocalMediaStore {
+    root: PathBuf,
+}
+
+impl LocalMediaStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path_of(&self, key: &str) -> PathBuf {
+        let prefix: String = key.chars().take(2).collect();
+        self.root.join(prefix).join(key)
+    }
+}
+
+#[async_trait::async_trait]
+impl MediaStore for LocalMediaStore {
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Bytes,
+        content_type: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.path_of(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write the content type first, an object is only visible once its
+        // content is in place.
+        tokio::fs::write(path.with_extension("type"), content_type).await?;
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, &body).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    async fn get_object(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Bytes, String)>, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.path_of(key);
+        let body = match tokio::fs::read(&path).await {
+            Ok(body) => body,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let content_type = tokio::fs::read_to_string(path.with_extension("type"))
+            .await
+            .unwrap_or_else(|_| "application/octet-stream".to_string());
+        Ok(Some((Bytes::from(body), content_type)))
+    }
+
+    async fn head_object(
+        &self,
+        key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(tokio::fs::try_exists(self.path_of(key)).await?)
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use reqwest::{header, redirect::Policy, Url};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::{MediaType, Message},
+    utils::config::Media,
+};
+
+pub mod local;
+
+pub use local::LocalMediaStore;
+
+/// Redirects followed when downloading a media.
+const MAX_REDIRECTS: usize = 5;
+
+/// Object storage backend for medias.
+///
+/// Modeled after the S3 object API so an S3-compatible backend can be dropped
+/// in next to [`LocalMediaStore`].
+#[async_trait::async_trait]
+pub trait MediaStore: Send + Sync {
+    /// Store an object, overwriting any object with the same key.
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Bytes,
+        content_type: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Fetch an object and its content type.
+    async fn get_object(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Bytes, String)>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Whether an object exists.
+    async fn head_object(
+        &self,
+        key: &str,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Content-addressed media storage.
+///
+/// Medias are keyed by the SHA-256 of their content and served back through
+/// `/media/{hash}`, so they outlive the (often expiring) upstream URLs.
+pub struct MediaService {
+    store: Arc<dyn MediaStore>,
+    base_url: String,
+    max_size: usize,
+    fetch_timeout: Duration,
+}
+
+impl MediaService {
+    pub fn new(store: Arc<dyn MediaStore>, config: &Media) -> Self {
+        Self {
+            store,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            max_size: config.max_size,
+            fetch_timeout: Duration::from_secs(config.fetch_timeout),
+        }
+    }
+
+    /// Local filesystem storage as configured.
+    pub fn from_config(config: &Media) -> Self {
+        Self::new(Arc::new(LocalMediaStore::new(&config.path)), config)
+    }
+
+    /// URL a stored media is served at.
+    pub fn url_of(&self, hash: &str) -> String {
+        format!("{}/media/{}", self.base_url, hash)
+    }
+
+    /// URL a stored media is served at to viewers of share `token`.
+    pub fn shared_url(&self, token: &str, hash: &str) -> String {
+        format!("{}/share/{}/media/{}", self.base_url, token, hash)
+    }
+
+    /// Hash of a media served by us, if `url` points to one.
+    pub fn hash_of<'a>(&self, url: &'a str) -> Option<&'a str> {
+        url.strip_prefix(&format!("{}/media/", self.base_url))
+            .filter(|hash| is_hash(hash))
+    }
+
+    /// Store media content of type `ty`, returning its URL.
+    ///
+    /// The content type is told from the content itself, and only the image
+    /// and audio formats of [`sniff()`] are kept, as medias are served back
+    /// on our origin.
+    pub async fn put(
+        &self,
+        body: Bytes,
+        ty: &MediaType,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let content_type = content_type_of(&body, ty).ok_or("unsupported media type")?;
+        let mut buf = [0u8; 64];
+        let hash = base16ct::lower::encode_str(&Sha256::digest(&body), &mut buf)
+            .unwrap()
+            .to_string();
+        if !self.store.head_object(&hash).await? {
+            self.store.put_object(&hash, body, content_type).await?;
+            log::debug!(target: "app", "Media `{hash}` stored");
+        }
+        Ok(self.url_of(&hash))
+    }
+
+    /// Store media content, falling back to embedding it as a `data:` URL
+    /// when the store is unavailable. `None` for unsupported content.
+    pub async fn put_or_embed(&self, body: Bytes, ty: &MediaType) -> Option<String> {
+        let content_type = content_type_of(&body, ty)?;
+        match self.put(body.clone(), ty).await {
+            Ok(url) => Some(url),
+            Err(e) => {
+                log::warn!(target: "app", "Unable to store media, embedding it instead: {e}");
+                Some(format!(
+                    "data:{content_type};base64,{}",
+                    base64::engine::general_purpose::STANDARD.encode(&body)
+                ))
+            }
+        }
+    }
+
+    /// Download the media behind `url` (`http(s)://` or `data:`) and store it,
+    /// returning the URL of the stored copy.
+    ///
+    /// Medias larger than `media.max_size` are refused, and so are hosts that
+    /// are not public, so clients cannot make us fetch internal services.
+    pub async fn store_url(
+        &self,
+        url: &str,
+        ty: &MediaType,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        if self.hash_of(url).is_some() {
+            return Ok(url.to_string());
+        }
+        let body = if let Some(data) = url.strip_prefix("data:") {
+            let (meta, content) = data.split_once(',').ok_or("malformed data URL")?;
+            let body = if meta.ends_with(";base64") {
+                // Checked before decoding, which would take that much memory.
+                if content.len() / 4 * 3 > self.max_size {
+                    return Err("media too large".into());
+                }
+                base64::engine::general_purpose::STANDARD.decode(content)?
+            } else {
+                content.as_bytes().to_vec()
+            };
+            if body.len() > self.max_size {
+                return Err("media too large".into());
+            }
+            Bytes::from(body)
+        } else {
+            self.fetch(url).await?
+        };
+        self.put(body, ty).await
+    }
+
+    /// Download `url`, following redirects as long as they stay on public
+    /// hosts.
+    async fn fetch(&self, url: &str) -> Result<Bytes, Box<dyn std::error::Error + Send + Sync>> {
+        let mut url = Url::parse(url)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let res = self.client_for(&url).await?.get(url.clone()).send().await?;
+            if res.status().is_redirection() {
+                let location = res
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or("redirect without location")?;
+                url = url.join(location)?;
+                continue;
+            }
+
+            let res = res.error_for_status()?;
+            if res
+                .content_length()
+                .is_some_and(|len| len > self.max_size as u64)
+            {
+                return Err("media too large".into());
+            }
+            let mut body = BytesMut::new();
+            let mut stream = res.bytes_stream();
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > self.max_size {
+                    return Err("media too large".into());
+                }
+                body.extend_from_slice(&chunk);
+            }
+            return Ok(body.freeze());
+        }
+        Err("too many redirects".into())
+    }
+
+    /// Client reaching the host of `url` only at the addresses checked here,
+    /// so a second DNS answer cannot point it somewhere else.
+    async fn client_for(
+        &self,
+        url: &Url,
+    ) -> Result<reqwest::Client, Box<dyn std::error::Error + Send + Sync>> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("unsupported scheme `{}`", url.scheme()).into());
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = match url.domain() {
+            Some(domain) => tokio::net::lookup_host((domain, port)).await?.collect(),
+            None => {
+                let host = url.host_str().ok_or("URL without host")?;
+                let ip: IpAddr = host.trim_matches(|c| c == '[' || c == ']').parse()?;
+                vec![SocketAddr::new(ip, port)]
+            }
+        };
+        if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+            return Err(format!("host of `{url}` is not public").into());
+        }
+
+        let mut builder = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .no_proxy()
+            .connect_timeout(self.fetch_timeout)
+            .timeout(self.fetch_timeout);
+        if let Some(domain) = url.domain() {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// URL upstream can fetch a media from. Stored medias are behind
+    /// authentication, so they are inlined as `data:` URLs, once `owned`
+    /// tells the caller may read them.
+    pub async fn public_url(&self, url: &str, owned: &HashSet<String>) -> String {
+        let Some(hash) = self.hash_of(url).filter(|hash| owned.contains(*hash)) else {
+            return url.to_string();
+        };
+        match self.get(hash).await {
+            Ok(Some((body, content_type))) => format!(
+                "data:{content_type};base64,{}",
+                base64::engine::general_purpose::STANDARD.encode(&body)
+            ),
+            Ok(None) => url.to_string(),
+            Err(e) => {
+                log::warn!(target: "app", "Unable to read media `{hash}`: {e}");
+                url.to_string()
+            }
+        }
+    }
+
+    /// Store every media of a message and point it to the stored copy.
+    ///
+    /// Medias failing to download keep their original URL, stored medias not
+    /// in `owned` are dropped: referring to them must not give access to
+    /// medias of other users. Returns whether anything changed, in which case
+    /// the message should be saved again.
+    pub async fn store_medias(&self, message: &mut Message, owned: &HashSet<String>) -> bool {
+        let Some(medias) = message.msg_medias.as_mut() else {
+            return false;
+        };
+        let before = medias.len();
+        medias.retain(|name, media| {
+            let foreign = self
+                .hash_of(&media.url)
+                .is_some_and(|hash| !owned.contains(hash));
+            if foreign {
+                log::warn!(target: "app", "Media `{name}` refers to a media of another user, dropped");
+            }
+            !foreign
+        });
+        let mut updated = medias.len() != before;
+        for (name, media) in medias.iter_mut() {
+            if self.hash_of(&media.url).is_some() {
+                continue;
+            }
+            match self.store_url(&media.url, &media.ty).await {
+                Ok(url) => {
+                    media.url = url;
+                    updated = true;
+                }
+                Err(e) => log::warn!(target: "app", "Unable to store media `{name}`: {e}"),
+            }
+        }
+        updated
+    }
+
+    /// Stored media `hash` and its content type. Medias stored before their
+    /// content was checked are left out unless they pass [`sniff()`].
+    pub async fn get(
+        &self,
+        hash: &str,
+    ) -> Result<Option<(Bytes, String)>, Box<dyn std::error::Error + Send + Sync>> {
+        if !is_hash(hash) {
+            return Ok(None);
+        }
+        Ok(self.store.get_object(hash).await?.and_then(|(body, _)| {
+            sniff(&body).map(|content_type| (body, content_type.to_string()))
+        }))
+    }
+}
+
+/// Content type of an image or audio, told from its first bytes. `None` for
+/// anything else, which is never stored nor served.
+pub fn sniff(body: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| body.get(offset..offset + magic.len()) == Some(magic);
+    let content_type = if at(0, b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if at(0, b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "image/gif"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "image/webp"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "audio/wav"
+    } else if at(0, b"OggS") {
+        "audio/ogg"
+    } else if at(0, b"fLaC") {
+        "audio/flac"
+    } else if at(0, b"ID3") {
+        "audio/mpeg"
+    } else if at(4, b"ftyp") {
+        "audio/mp4"
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        "audio/webm"
+    } else {
+        // Raw frames: ADTS (AAC), then MPEG audio.
+        match body {
+            [0xff, second, ..] if second & 0xf6 == 0xf0 => "audio/aac",
+            [0xff, second, ..] if second & 0xe0 == 0xe0 && second & 0x06 != 0 => "audio/mpeg",
+            _ => return None,
+        }
+    };
+    Some(content_type)
+}
+
+/// Content type of `body` if it is a supported media of type `ty`.
+pub fn content_type_of(body: &[u8], ty: &MediaType) -> Option<&'static str> {
+    let content_type = sniff(body)?;
+    let expected = match ty {
+        MediaType::Image => "image/",
+        MediaType::Audio => "audio/",
+        MediaType::Video => return None,
+    };
+    content_type.starts_with(expected).then_some(content_type)
+}
+
+/// Whether `ip` is a public address, not loopback, private, link-local or
+/// otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space, benchmarking, reserved.
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Whether `hash` looks like a hex encoded SHA-256.
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
@@ -0,0 +1,110 @@
+//! Prometheus metrics, exposed on `/metrics`.
+
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use sqlx::MySqlPool;
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Registry =
+        Registry::new_custom(Some("rustybot".to_string()), None).unwrap();
+
+    /// Requests served, by route pattern and status.
+    pub static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests served"),
+        &["method", "route", "status"],
+    ));
+
+    /// Time to the response head, streams are not waited for.
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+        &["method", "route"],
+    ));
+
+    /// Failed upstream attempts, retried or not, by HTTP status or failure kind.
+    pub static ref UPSTREAM_ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("upstream_errors_total", "Failed upstream requests"),
+        &["endpoint", "kind"],
+    ));
+
+    /// Tokens reported by upstream usage, by model and user ID.
+    pub static ref TOKENS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("tokens_total", "Tokens used"),
+        &["model", "user", "kind"],
+    ));
+
+    /// Upstream streams being relayed to clients.
+    pub static ref ACTIVE_STREAMS: IntGauge = register(IntGauge::new(
+        "active_streams",
+        "Streams being relayed",
+    ));
+
+    static ref DB_POOL_CONNECTIONS: IntGauge = register(IntGauge::new(
+        "db_pool_connections",
+        "Open database connections",
+    ));
+
+    static ref DB_POOL_IDLE: IntGauge = register(IntGauge::new(
+        "db_pool_idle_connections",
+        "Idle database connections",
+    ));
+}
+
+fn register<T: Collector + Clone + 'static>(collector: prometheus::Result<T>) -> T {
+    let collector = collector.unwrap();
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+/// Count a failed upstream attempt.
+pub fn upstream_error(endpoint: &str, kind: &str) {
+    UPSTREAM_ERRORS.with_label_values(&[endpoint, kind]).inc();
+}
+
+/// Count the tokens of an OpenAI `usage` object.
+pub fn record_usage(model: &str, user_id: i32, usage: &serde_json::Value) {
+    let user = user_id.to_string();
+    for (field, kind) in [
+        ("prompt_tokens", "prompt"),
+        ("completion_tokens", "completion"),
+    ] {
+        if let Some(tokens) = usage[field].as_u64() {
+            TOKENS
+                .with_label_values(&[model, &user, kind])
+                .inc_by(tokens);
+        }
+    }
+}
+
+/// Counts a stream as active for as long as it is alive.
+pub struct ActiveStream;
+
+impl ActiveStream {
+    pub fn start() -> Self {
+        ACTIVE_STREAMS.inc();
+        Self
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        ACTIVE_STREAMS.dec();
+    }
+}
+
+/// Every metric in the Prometheus text format, with the usage of pool `db`
+/// when there is one.
+pub fn render(db: Option<&MySqlPool>) -> String {
+    if let Some(db) = db {
+        DB_POOL_CONNECTIONS.set(db.size() as i64);
+        DB_POOL_IDLE.set(db.num_idle() as i64);
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
@@ -1,16 +1,41 @@
-use std::rc::Rc;
+use std::{
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    time::Instant,
+};
 
 use actix_service::Transform;
 use actix_web::{
+    body::{BodySize, MessageBody},
     dev::{Service, ServiceRequest, ServiceResponse},
-    error, Error, HttpMessage,
+    error,
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        StatusCode,
+    },
+    web, Error, HttpMessage,
 };
+use bytes::Bytes;
 use futures::{
     future::{ready, LocalBoxFuture, Ready},
     FutureExt,
 };
+use tracing::Instrument;
+
+use crate::{
+    auth::auth_with_db,
+    metrics,
+    models::Store,
+    types::error::error_response,
+    utils::{
+        logging::{enter_request_id, with_request_id},
+        tls::ClientIdentity,
+    },
+};
 
-use crate::auth::auth_with_db;
+/// Header carrying the request ID, both ways.
+const REQUEST_ID: &str = "x-request-id";
 
 pub type AuthenticationInfo = Rc<bool>;
 pub struct AuthenticateMiddleware<S> {
@@ -26,20 +51,24 @@ where
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     actix_service::forward_ready!(service);
-    fn call(&self, req: ServiceRequest) -> Self::Future {
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
         // Clone the Rc pointers so we can move them into the async block.
         let srv = self.service.clone();
 
         async move {
             // See if we can match it to a user.
-            let auth = authenticate(&req).await;
+            let auth = authenticate(&mut req).await;
             if auth {
                 // If we found a user, add it to the request extensions
                 // for later retrieval.
                 req.extensions_mut()
                     .insert::<AuthenticationInfo>(Rc::new(auth));
             } else {
-                return Err(error::ErrorUnauthorized("Authentication failed."));
+                return Err(error::InternalError::from_response(
+                    "Authentication failed.",
+                    error_response(StatusCode::UNAUTHORIZED, "Authentication failed."),
+                )
+                .into());
             }
 
             let res = srv.call(req).await?;
@@ -50,6 +79,7 @@ where
     }
 }
 
+#[derive(Default)]
 pub struct AuthenticateMiddlewareFactory {}
 
 impl AuthenticateMiddlewareFactory {
@@ -75,17 +105,411 @@ where
     }
 }
 
-async fn authenticate(req: &ServiceRequest) -> bool {
+/// Model a request was admitted for, logged with it.
+#[derive(Debug, Clone)]
+pub struct RequestModel(pub String);
+
+/// Token usage reported by upstream, logged with the response carrying it.
+#[derive(Debug, Clone, Default)]
+pub struct TokenUsage {
+    pub prompt: u64,
+    pub completion: u64,
+}
+
+impl TokenUsage {
+    /// Usage of an OpenAI `usage` object, missing counts being zero.
+    pub fn of(usage: &serde_json::Value) -> Self {
+        Self {
+            prompt: usage["prompt_tokens"].as_u64().unwrap_or_default(),
+            completion: usage["completion_tokens"].as_u64().unwrap_or_default(),
+        }
+    }
+}
+
+/// Give every request an ID, from `x-request-id` or generated, which tags all
+/// it logs and is sent back, then log the request as a JSON line on the
+/// `access` target.
+///
+/// Streamed responses are logged once their body ends, with the token usage
+/// their events reported and the whole time they took.
+pub struct RequestLogMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<LoggedBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= 128)
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        let span = tracing::info_span!(
+            "http_request",
+            otel.name = %format!("{} {}", req.method(), req.match_pattern().unwrap_or_else(|| req.path().to_string())),
+            request_id = %request_id,
+            http.method = %req.method(),
+            http.target = %req.path(),
+            http.status_code = tracing::field::Empty,
+        );
+
+        with_request_id(request_id.clone(), async move {
+            let started = Instant::now();
+            let method = req.method().to_string();
+            let path = req.path().to_string();
+            let res = srv.call(req).await;
+
+            let mut entry = serde_json::json!({
+                "request_id": request_id,
+                "method": method,
+                "path": path,
+            });
+            let mut streamed = false;
+            match res.as_ref() {
+                Ok(res) => {
+                    let request = res.request();
+                    if request.extensions().contains::<AuthenticationInfo>() {
+                        if let Some(user) = header_str(request.headers().get("x-rustybot-id")) {
+                            entry["user"] = serde_json::json!(user);
+                        }
+                    }
+                    if let Some(RequestModel(model)) = request.extensions().get::<RequestModel>() {
+                        entry["model"] = serde_json::json!(model);
+                    }
+                    entry["status"] = serde_json::json!(res.status().as_u16());
+                    if let Some(chat_id) = header_str(res.headers().get("x-rustybot-chat-id")) {
+                        entry["chat_id"] = serde_json::json!(chat_id.parse::<i32>().ok());
+                    }
+                    if let Some(usage) = res.response().extensions().get::<TokenUsage>() {
+                        entry["prompt_tokens"] = serde_json::json!(usage.prompt);
+                        entry["completion_tokens"] = serde_json::json!(usage.completion);
+                    }
+                    streamed = matches!(res.response().body().size(), BodySize::Stream);
+                }
+                Err(e) => {
+                    entry["status"] =
+                        serde_json::json!(e.as_response_error().status_code().as_u16());
+                }
+            }
+            tracing::Span::current().record("http.status_code", entry["status"].as_u64());
+            if !streamed {
+                entry["latency_ms"] = serde_json::json!(started.elapsed().as_millis() as u64);
+                log::info!(target: "access", "{entry}");
+            }
+
+            let mut res = res?;
+            if let Ok(id) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID), id);
+            }
+            let events = res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .is_some_and(|ty| ty.as_bytes().starts_with(b"text/event-stream"));
+            Ok(res.map_body(|_, body| LoggedBody {
+                body: Box::pin(body),
+                request_id,
+                entry: streamed.then_some(entry),
+                started,
+                usage: events.then(UsageScanner::default),
+            }))
+        })
+        .instrument(span)
+        .boxed_local()
+    }
+}
+
+/// Response body polled with the request ID in the MDC, as its request was,
+/// so what the stream logs is tagged too.
+pub struct LoggedBody<B> {
+    body: Pin<Box<B>>,
+    request_id: String,
+
+    /// Access log entry still to be written, once the body ends.
+    entry: Option<serde_json::Value>,
+    started: Instant,
+
+    /// Usage found in the events of a streamed completion so far.
+    usage: Option<UsageScanner>,
+}
+
+impl<B: MessageBody> MessageBody for LoggedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = &mut *self;
+        let _id = enter_request_id(&this.request_id);
+        let polled = this.body.as_mut().poll_next(cx);
+        if let (Poll::Ready(Some(Ok(bytes))), Some(usage)) = (&polled, this.usage.as_mut()) {
+            usage.scan(bytes);
+        }
+        polled
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        // Whether the stream ended or the client went away.
+        let Some(mut entry) = self.entry.take() else {
+            return;
+        };
+        let _id = enter_request_id(&self.request_id);
+        if let Some(usage) = self.usage.as_ref().and_then(|usage| usage.usage.as_ref()) {
+            entry["prompt_tokens"] = serde_json::json!(usage.prompt);
+            entry["completion_tokens"] = serde_json::json!(usage.completion);
+        }
+        entry["latency_ms"] = serde_json::json!(self.started.elapsed().as_millis() as u64);
+        log::info!(target: "access", "{entry}");
+    }
+}
+
+/// Finds the `usage` sent in the events of a stream, with
+/// `stream_options.include_usage`.
+#[derive(Default)]
+struct UsageScanner {
+    /// Start of a line cut by the chunk it came in.
+    pending: Vec<u8>,
+    usage: Option<TokenUsage>,
+}
+
+impl UsageScanner {
+    fn scan(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        let Some(end) = self.pending.iter().rposition(|byte| *byte == b'\n') else {
+            return;
+        };
+        let lines: Vec<u8> = self.pending.drain(..=end).collect();
+        for line in String::from_utf8_lossy(&lines).lines() {
+            let Some(event) = line.trim().strip_prefix("data: ") else {
+                continue;
+            };
+            if !event.contains("\"usage\"") {
+                continue;
+            }
+            if let Ok(event) = serde_json::from_str::<serde_json::Value>(event) {
+                if event["usage"].is_object() {
+                    self.usage = Some(TokenUsage::of(&event["usage"]));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RequestLogMiddlewareFactory {}
+
+impl RequestLogMiddlewareFactory {
+    pub fn new() -> Self {
+        RequestLogMiddlewareFactory {}
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLogMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<LoggedBody<B>>;
+    type Error = Error;
+    type Transform = RequestLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestLogMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+fn header_str(value: Option<&HeaderValue>) -> Option<&str> {
+    value.and_then(|value| value.to_str().ok())
+}
+
+/// Count requests and time them for `/metrics`.
+pub struct MetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+
+        async move {
+            let started = Instant::now();
+            let method = req.method().to_string();
+            let res = srv.call(req).await;
+
+            // Route patterns rather than paths, so IDs do not each get a series.
+            // Requests failed before routing, by authentication, have none.
+            let (route, status) = match res.as_ref() {
+                Ok(res) => (
+                    res.request()
+                        .match_pattern()
+                        .unwrap_or_else(|| "unmatched".to_string()),
+                    res.status(),
+                ),
+                Err(e) => ("unmatched".to_string(), e.as_response_error().status_code()),
+            };
+            metrics::HTTP_REQUESTS
+                .with_label_values(&[&method, &route, status.as_str()])
+                .inc();
+            metrics::HTTP_REQUEST_DURATION
+                .with_label_values(&[&method, &route])
+                .observe(started.elapsed().as_secs_f64());
+            res
+        }
+        .boxed_local()
+    }
+}
+
+#[derive(Default)]
+pub struct MetricsMiddlewareFactory {}
+
+impl MetricsMiddlewareFactory {
+    pub fn new() -> Self {
+        MetricsMiddlewareFactory {}
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MetricsMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = MetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn authenticate(req: &mut ServiceRequest) -> bool {
+    let Some(store) = req.app_data::<web::Data<Store>>().cloned() else {
+        log::error!(target: "app", "Authentication failed due to no database configured");
+        return false;
+    };
+    if let Some(ClientIdentity(name)) = req.conn_data::<ClientIdentity>().cloned() {
+        return auth_with_certificate(req, &store, &name).await;
+    }
+
     let header_hash = req.headers().get("x-rustybot-hash");
     let header_salt = req.headers().get("x-rustybot-salt");
     let header_id = req.headers().get("x-rustybot-id");
-    if header_hash.is_some() && header_salt.is_some() && header_id.is_some() {
-        let hash = header_hash.unwrap().to_str().unwrap();
-        let salt = header_salt.unwrap().to_str().unwrap();
-        let id = header_id.unwrap().to_str().unwrap();
+    if let (Some(hash), Some(salt), Some(id)) = (header_hash, header_salt, header_id) {
+        let hash = hash.to_str().unwrap();
+        let salt = salt.to_str().unwrap();
+        let id = id.to_str().unwrap();
         // auth_with_file(id, hash, salt)
-        auth_with_db(id, hash, salt).await
+        auth_with_db(&store, id, hash, salt).await
     } else {
         false
     }
 }
+
+/// Authenticate the user named by a verified client certificate. Handlers
+/// find the user by `x-rustybot-id`, which is set to that name whatever the
+/// client sent.
+async fn auth_with_certificate(req: &mut ServiceRequest, store: &Store, name: &str) -> bool {
+    match store.users.find_by_name(name).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            log::warn!(target: "app", "Authentication failed due to no user `{}` for client certificate", name);
+            return false;
+        }
+        Err(_) => {
+            log::warn!(target: "app", "Authentication failed due to database query failed.");
+            return false;
+        }
+    }
+    let Ok(value) = HeaderValue::from_str(name) else {
+        return false;
+    };
+    req.headers_mut()
+        .insert(HeaderName::from_static("x-rustybot-id"), value);
+    log::debug!(target: "app", "Authentication passed with client certificate");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::body::{self, BodyStream};
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::utils::logging;
+
+    #[test]
+    fn usage_is_found_across_chunks() {
+        let mut scanner = UsageScanner::default();
+        let events = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}],\"usage\":null}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":2}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        for chunk in events.as_bytes().chunks(7) {
+            scanner.scan(chunk);
+        }
+        let usage = scanner.usage.unwrap();
+        assert_eq!((usage.prompt, usage.completion), (9, 2));
+    }
+
+    #[actix_web::test]
+    async fn body_is_polled_with_request_id() {
+        let seen: Arc<Mutex<Vec<Option<String>>>> = Default::default();
+        let recorder = seen.clone();
+        let stream = futures::stream::iter(["a", "b"]).map(move |chunk| {
+            recorder.lock().unwrap().push(logging::request_id());
+            Ok::<_, std::io::Error>(Bytes::from(chunk))
+        });
+        let body = LoggedBody {
+            body: Box::pin(BodyStream::new(stream)),
+            request_id: "req-1".to_string(),
+            entry: Some(serde_json::json!({})),
+            started: Instant::now(),
+            usage: Some(UsageScanner::default()),
+        };
+
+        assert_eq!(body::to_bytes(body).await.unwrap(), "ab");
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [Some("req-1".to_string()), Some("req-1".to_string())]
+        );
+        assert_eq!(logging::request_id(), None);
+    }
+}
@@ -6,4 +6,14 @@ pub struct Chat {
     pub chat_created_at: DateTime<Utc>,
     pub chat_date: NaiveDate,
     pub chat_summary: Option<String>,
-}
\ No newline at end of file
+
+    /// Last message of the selected branch, `None` for the latest message.
+    ///
+    /// Added to existing databases with:
+    ///
+    /// ```sql
+    /// ALTER TABLE `tbl_chat` ADD COLUMN `chat_leaf_msg_id` INT NULL;
+    /// ```
+    #[sqlx(default)]
+    pub chat_leaf_msg_id: Option<i32>,
+}
@@ -1,33 +1,40 @@
 use crate::{
-    models::{Auth, User},
-    utils::sql::check_sql_component,
+    models::Auth,
+    utils::{logging::redact_sql, sql::check_sql_component},
 };
 use rustybot_macros::get_connection;
 use sha2::{Digest, Sha512};
-use sqlx::Acquire;
+use sqlx::{Acquire, MySqlPool};
 
 impl Auth {
     /// Directly query user's authentication information by given name.
     ///
     /// # Arguments
     /// - `id`: login name of the user. Not its database ID.
-    pub async fn auth(id: &str) -> Result<Option<Auth>, Box<dyn std::error::Error>> {
-        if let Ok(id) = check_sql_component(id) {
-            get_connection!();
-
-            let sql_raw = format!("SELECT a.* FROM `tbl_auth` a LEFT JOIN `tbl_user` u ON a.auth_user_id = u.user_id WHERE u.user_name = '{}'", id);
-            log::debug!(target: "sql", "{sql_raw}");
-            Ok(sqlx::query_as(&sql_raw)
-                .fetch_optional(&mut connection)
-                .await
-                .unwrap())
-        } else {
-            Err(format!("Invalid SQL component `{id}`").into())
-        }
+    #[tracing::instrument(name = "Auth::auth", skip_all)]
+    pub async fn auth(
+        db: &MySqlPool,
+        id: &str,
+    ) -> Result<Option<Auth>, Box<dyn std::error::Error>> {
+        // Checked apart so that no error is held across queries.
+        let Ok(id) = check_sql_component(id) else {
+            return Err(format!("Invalid SQL component `{id}`").into());
+        };
+        get_connection!(db);
+
+        let sql_raw = format!("SELECT a.* FROM `tbl_auth` a LEFT JOIN `tbl_user` u ON a.auth_user_id = u.user_id WHERE u.user_name = '{}'", id);
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw)
+            .fetch_optional(&mut connection)
+            .await
+            .unwrap())
     }
 
-    pub(in crate::models) async fn create(&self) -> Auth {
-        get_connection!();
+    pub(in crate::models) async fn create(
+        &self,
+        db: &MySqlPool,
+    ) -> Result<Auth, Box<dyn std::error::Error>> {
+        get_connection!(db);
 
         let mut key_pairs: Vec<&str> = vec![];
         let mut value_pairs: Vec<String> = vec![];
@@ -44,7 +51,7 @@ impl Auth {
             value_pairs.join(", ")
         );
 
-        log::debug!(target: "sql", "{}", query_string);
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
 
         let mut trans = connection.begin().await.unwrap();
 
@@ -60,11 +67,7 @@ impl Auth {
                 .unwrap();
 
         trans.commit().await.unwrap();
-        auth
-    }
-
-    pub(in crate::models) async fn user(&self) -> User {
-        User::find_by_id(self.auth_user_id).await.unwrap().unwrap()
+        Ok(auth)
     }
 }
 
@@ -79,7 +82,7 @@ impl Auth {
     }
 
     pub fn id(&self) -> Option<i32> {
-        self.auth_id.clone()
+        self.auth_id
     }
 
     pub fn key(&self) -> String {
@@ -88,10 +91,10 @@ impl Auth {
 }
 
 impl Auth {
-    pub async fn hash(&self, salt: &str) -> String {
+    /// Hash the client must send to authenticate as user `name`.
+    pub fn hash(&self, name: &str, salt: &str) -> String {
         let mut buf = [0u8; 1024];
-        let user = self.user().await;
-        let input = format!("{}{}{}", user.user_name, self.auth_key, salt);
+        let input = format!("{}{}{}", name, self.auth_key, salt);
         let mut hasher: Sha512 = Sha512::new();
         hasher.update(input.as_bytes());
         let hash = hasher.finalize();
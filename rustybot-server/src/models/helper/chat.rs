@@ -1,7 +1,12 @@
-use crate::models::{Chat, Message};
+use std::collections::HashMap;
+
+use crate::{
+    models::{Chat, Message, Store},
+    utils::{logging::redact_sql, sql::escape_string},
+};
 use chrono::Utc;
 use rustybot_macros::get_connection;
-use sqlx::Acquire;
+use sqlx::{Acquire, MySqlPool};
 
 impl Chat {
     /// Create a new chat entity that can be saved to database.
@@ -12,24 +17,119 @@ impl Chat {
             chat_created_at: Utc::now(),
             chat_date: Utc::now().date_naive(),
             chat_summary: None,
+            chat_leaf_msg_id: None,
         }
     }
 
-    /// Get message history of current chat entity.
-    pub async fn history(&self) -> Vec<Message> {
+    /// Get message history of current chat entity, following the selected
+    /// branch.
+    pub async fn history(&self, store: &Store) -> Vec<Message> {
+        match self.chat_leaf_msg_id {
+            Some(leaf) => self.history_to(store, leaf).await,
+            None => {
+                let Some(chat_id) = self.chat_id else {
+                    return vec![];
+                };
+                let messages = store.messages.find_by_chat(chat_id).await.unwrap();
+                match messages.last().and_then(|msg| msg.msg_id) {
+                    Some(leaf) => branch_to(messages, leaf),
+                    None => vec![],
+                }
+            }
+        }
+    }
+
+    /// Get message history of current chat entity, from its first message to
+    /// message `leaf`.
+    pub async fn history_to(&self, store: &Store, leaf: i32) -> Vec<Message> {
         if self.chat_id.is_none() {
             return vec![];
         }
-        Message::find_messages_by_chat(self.chat_id.clone().unwrap())
+        let messages = store
+            .messages
+            .find_by_chat(self.chat_id.unwrap())
             .await
-            .unwrap()
+            .unwrap();
+        branch_to(messages, leaf)
+    }
+
+    /// Last message of the selected branch.
+    pub async fn leaf(&self, store: &Store) -> Option<i32> {
+        if self.chat_leaf_msg_id.is_some() {
+            return self.chat_leaf_msg_id;
+        }
+        self.history(store).await.last().and_then(|msg| msg.msg_id)
+    }
+
+    /// Save a NEW message as the last one of the selected branch.
+    pub async fn append(
+        &mut self,
+        store: &Store,
+        message: Message,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let parent_id = self.leaf(store).await;
+        self.branch(store, parent_id, message).await
+    }
+
+    /// Save a NEW message following `parent_id` and select its branch. When
+    /// `parent_id` already has replies, this starts a new branch.
+    pub async fn branch(
+        &mut self,
+        store: &Store,
+        parent_id: Option<i32>,
+        mut message: Message,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        message.msg_parent_id = parent_id;
+        let message = store.messages.save(&message).await?;
+        store.chats.set_leaf(self, message.msg_id.unwrap()).await?;
+        Ok(message)
+    }
+
+    /// Select the branch going through message `mid`, following its latest
+    /// replies down to the end.
+    pub async fn select(
+        &mut self,
+        store: &Store,
+        mid: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let messages = store
+            .messages
+            .find_by_chat(self.chat_id.ok_or("Chat not saved to database yet")?)
+            .await?;
+        let mut leaf = mid;
+        // Messages are sorted by ID, so the last reply found is the latest.
+        while let Some(reply) = messages
+            .iter()
+            .rev()
+            .find(|msg| msg.msg_parent_id == Some(leaf))
+        {
+            leaf = reply.msg_id.unwrap();
+        }
+        store.chats.set_leaf(self, leaf).await
+    }
+}
+
+/// Messages leading to message `leaf`, first one first.
+fn branch_to(messages: Vec<Message>, leaf: i32) -> Vec<Message> {
+    let mut by_id: HashMap<i32, Message> = messages
+        .into_iter()
+        .map(|msg| (msg.msg_id.unwrap(), msg))
+        .collect();
+    let mut history = vec![];
+    let mut next = Some(leaf);
+    while let Some(msg) = next.and_then(|mid| by_id.remove(&mid)) {
+        next = msg.msg_parent_id;
+        history.push(msg);
     }
+    history.reverse();
+    history
 }
 
 /// Methods that implement SQL operations.
 impl Chat {
-    pub async fn save(&self) -> Result<Self, Box<dyn std::error::Error>> {
-        get_connection!();
+    #[tracing::instrument(name = "Chat::save", skip_all)]
+    pub async fn save(&self, db: &MySqlPool) -> Result<Self, Box<dyn std::error::Error>> {
+        get_connection!(db);
 
         let mut key_pairs: Vec<&str> = vec![];
         let mut value_pairs: Vec<String> = vec![];
@@ -43,9 +143,14 @@ impl Chat {
         key_pairs.push("`chat_date`");
         value_pairs.push(format!("'{}'", self.chat_date.format("%F")));
 
-        if self.chat_summary.is_some() {
+        if let Some(summary) = self.chat_summary.as_ref() {
             key_pairs.push("`chat_summary`");
-            value_pairs.push(format!("'{}'", self.chat_summary.clone().unwrap()));
+            value_pairs.push(format!("'{}'", escape_string(summary)));
+        }
+
+        if let Some(leaf_id) = self.chat_leaf_msg_id {
+            key_pairs.push("`chat_leaf_msg_id`");
+            value_pairs.push(format!("{}", leaf_id));
         }
 
         let query_string = format!(
@@ -54,7 +159,7 @@ impl Chat {
             value_pairs.join(", ")
         );
 
-        log::debug!(target: "sql", "{}", query_string);
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
 
         let mut trans = connection.begin().await.unwrap();
 
@@ -73,31 +178,61 @@ impl Chat {
         Ok(chat)
     }
 
-    pub async fn find_chats_by_user(uid: i32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
-        get_connection!();
+    /// Save the last message of the selected branch of an EXISTING chat.
+    #[tracing::instrument(name = "Chat::set_leaf", skip_all)]
+    pub async fn set_leaf(
+        &mut self,
+        db: &MySqlPool,
+        mid: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let chat_id = self.chat_id.ok_or("Chat not saved to database yet")?;
+
+        get_connection!(db);
+
+        let query_string = format!(
+            "UPDATE `tbl_chat` SET `tbl_chat`.`chat_leaf_msg_id` = {} WHERE `tbl_chat`.`chat_id` = {}",
+            mid, chat_id
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
+
+        sqlx::query(&query_string).execute(&mut connection).await?;
+        self.chat_leaf_msg_id = Some(mid);
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Chat::find_chats_by_user", skip_all)]
+    pub async fn find_chats_by_user(
+        db: &MySqlPool,
+        uid: i32,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
 
         let sql_raw = format!(
             "SELECT * FROM `tbl_chat` WHERE `tbl_chat`.`chat_user_id` = {}",
             uid
         );
-        log::debug!(target: "sql", "{sql_raw}");
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
         Ok(sqlx::query_as(&sql_raw)
             .fetch_all(&mut connection)
             .await
             .unwrap())
     }
 
-    pub async fn chat_by_id(cid: i32) -> Result<Option<Chat>, Box<dyn std::error::Error>> {
-      get_connection!();
-
-      let sql_raw = format!(
-          "SELECT * FROM `tbl_chat` WHERE `tbl_chat`.`chat_id` = {}",
-          cid
-      );
-      log::debug!(target: "sql", "{sql_raw}");
-      Ok(sqlx::query_as(&sql_raw)
-          .fetch_optional(&mut connection)
-          .await
-          .unwrap())
-  }
+    #[tracing::instrument(name = "Chat::chat_by_id", skip_all)]
+    pub async fn chat_by_id(
+        db: &MySqlPool,
+        cid: i32,
+    ) -> Result<Option<Chat>, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let sql_raw = format!(
+            "SELECT * FROM `tbl_chat` WHERE `tbl_chat`.`chat_id` = {}",
+            cid
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw)
+            .fetch_optional(&mut connection)
+            .await
+            .unwrap())
+    }
 }
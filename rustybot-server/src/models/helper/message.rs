@@ -1,9 +1,13 @@
 use std::collections::HashMap;
 
-use crate::models::{Message, MessageMedia, MessageSender, MessageModel};
+use crate::{
+    models::{MediaType, Message, MessageMedia, MessageModel, MessageSearch, MessageSender},
+    types::chat::{ContentPart, MessageContent},
+    utils::{logging::redact_sql, sql::escape_string},
+};
 use chrono::Utc;
 use rustybot_macros::get_connection;
-use sqlx::{types::Json, Acquire};
+use sqlx::{types::Json, Acquire, MySqlPool};
 
 impl Message {
     /// Create a new chat entity that can be saved to database.
@@ -17,16 +21,16 @@ impl Message {
         content: String,
         media: Option<HashMap<String, MessageMedia>>,
     ) -> Self {
-        let formed_media: Option<Json<HashMap<String, Json<MessageMedia>>>> = if media.is_none() {
-            None
-        } else {
-            let map = media.unwrap();
-            let mut updated_map: HashMap<String, Json<MessageMedia>> = HashMap::new();
-            map.iter().for_each(|(key, med)| {
-                updated_map.insert(key.clone(), Json::<_>(med.clone()));
-            });
-            Some(Json::<_>(updated_map))
-        };
+        let formed_media: Option<Json<HashMap<String, Json<MessageMedia>>>> =
+            if let Some(map) = media {
+                let mut updated_map: HashMap<String, Json<MessageMedia>> = HashMap::new();
+                map.iter().for_each(|(key, med)| {
+                    updated_map.insert(key.clone(), Json::<_>(med.clone()));
+                });
+                Some(Json::<_>(updated_map))
+            } else {
+                None
+            };
         Self {
             msg_id: None,
             msg_chat_id: cid,
@@ -35,15 +39,64 @@ impl Message {
             msg_content: content,
             msg_medias: formed_media,
             msg_created_at: Utc::now(),
+            msg_parent_id: None,
+        }
+    }
+
+    /// Create a new message from request content. Images of multi-part
+    /// content become medias referenced by `${{image_N}}` placeholders.
+    pub fn from_content(
+        cid: i32,
+        model: MessageModel,
+        sender: MessageSender,
+        content: &MessageContent,
+    ) -> Self {
+        let MessageContent::Parts(parts) = content else {
+            return Self::new(cid, model, sender, content.to_text(), None);
+        };
+
+        let mut lines: Vec<String> = vec![];
+        let mut medias: HashMap<String, MessageMedia> = HashMap::new();
+        for part in parts {
+            match part {
+                ContentPart::Text { text } => lines.push(text.clone()),
+                ContentPart::ImageUrl { image_url } => {
+                    let name = format!("image_{}", medias.len());
+                    lines.push(format!("${{{{{name}}}}}"));
+                    medias.insert(
+                        name,
+                        MessageMedia {
+                            ty: MediaType::Image,
+                            url: image_url.url.clone(),
+                        },
+                    );
+                }
+            }
         }
+        let medias = if medias.is_empty() {
+            None
+        } else {
+            Some(medias)
+        };
+        Self::new(cid, model, sender, lines.join("\n"), medias)
+    }
+
+    /// Attach a media to this message, replacing any media of the same name.
+    ///
+    /// Call [`update_medias()`][`Message::update_medias()`] to save it.
+    pub fn set_media(&mut self, name: &str, media: MessageMedia) {
+        self.msg_medias
+            .get_or_insert_with(|| Json(HashMap::new()))
+            .insert(name.to_string(), Json(media));
     }
 }
 
 /// Methods that implement SQL operations.
 impl Message {
     /// Save NEW message into database.
-    pub async fn save(&self) -> Result<Self, Box<dyn std::error::Error>> {
-        get_connection!();
+    #[tracing::instrument(name = "Message::save", skip_all)]
+    pub async fn save(&self, db: &MySqlPool) -> Result<Self, Box<dyn std::error::Error>> {
+        get_connection!(db);
 
         let mut key_pairs: Vec<&str> = vec![];
         let mut value_pairs: Vec<String> = vec![];
@@ -52,34 +105,37 @@ impl Message {
         value_pairs.push(format!("{}", self.msg_chat_id));
 
         key_pairs.push("`msg_model`");
-        value_pairs.push(format!("{}", Into::<i8>::into(self.msg_model.clone())));
+        value_pairs.push(format!("{}", Into::<i8>::into(self.msg_model)));
 
         key_pairs.push("`msg_sender`");
         value_pairs.push(format!("{}", Into::<i8>::into(self.msg_sender.clone())));
 
         key_pairs.push("`msg_content`");
-        value_pairs.push(format!("'{}'", self.msg_content.clone().replace("'", "''")));
+        value_pairs.push(format!("'{}'", escape_string(&self.msg_content)));
 
         if self.msg_medias.is_some() {
             key_pairs.push("`msg_medias`");
             value_pairs.push(format!(
                 "'{}'",
-                serde_json::to_string(&self.msg_medias.clone().unwrap())
-                    .unwrap()
-                    .replace("'", "''")
+                escape_string(&serde_json::to_string(&self.msg_medias.clone().unwrap()).unwrap())
             ));
         }
 
         key_pairs.push("`msg_created_at`");
         value_pairs.push(format!("'{}'", self.msg_created_at.format("%+")));
 
+        if let Some(parent_id) = self.msg_parent_id {
+            key_pairs.push("`msg_parent_id`");
+            value_pairs.push(format!("{}", parent_id));
+        }
+
         let query_string = format!(
             "INSERT INTO `tbl_msg` ({}) VALUES ({})",
             key_pairs.join(", "),
             value_pairs.join(", ")
         );
 
-        log::debug!(target: "sql", "{}", query_string);
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
 
         let mut trans = connection.begin().await.unwrap();
 
@@ -98,17 +154,160 @@ impl Message {
         Ok(msg)
     }
 
-    pub async fn find_messages_by_chat(cid: i32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
-        get_connection!();
+    #[tracing::instrument(name = "Message::find_messages_by_chat", skip_all)]
+    pub async fn find_messages_by_chat(
+        db: &MySqlPool,
+        cid: i32,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
 
         let sql_raw = format!(
-            "SELECT * FROM `tbl_msg` WHERE `tbl_msg`.`msg_chat_id` = {}",
+            "SELECT * FROM `tbl_msg` WHERE `tbl_msg`.`msg_chat_id` = {} ORDER BY `tbl_msg`.`msg_id`",
             cid
         );
-        log::debug!(target: "sql", "{sql_raw}");
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
         Ok(sqlx::query_as(&sql_raw)
             .fetch_all(&mut connection)
             .await
             .unwrap())
     }
+
+    #[tracing::instrument(name = "Message::find_by_id", skip_all)]
+    pub async fn find_by_id(
+        db: &MySqlPool,
+        mid: i32,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let sql_raw = format!("SELECT * FROM `tbl_msg` WHERE `tbl_msg`.`msg_id` = {}", mid);
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw)
+            .fetch_optional(&mut connection)
+            .await?)
+    }
+
+    /// Every version of this message: messages of the same sender following
+    /// the same parent, itself included, oldest first.
+    #[tracing::instrument(name = "Message::versions", skip_all)]
+    pub async fn versions(&self, db: &MySqlPool) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let parent_id = match self.msg_parent_id {
+            Some(parent_id) => format!("{}", parent_id),
+            None => "NULL".to_string(),
+        };
+        let sql_raw = format!(
+            "SELECT * FROM `tbl_msg` WHERE `tbl_msg`.`msg_chat_id` = {} AND `tbl_msg`.`msg_parent_id` <=> {} AND `tbl_msg`.`msg_sender` = {} ORDER BY `tbl_msg`.`msg_id`",
+            self.msg_chat_id,
+            parent_id,
+            Into::<i8>::into(self.msg_sender.clone())
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw).fetch_all(&mut connection).await?)
+    }
+
+    /// Whether stored media `hash` is attached to a message in a chat of user
+    /// `uid`.
+    #[tracing::instrument(name = "Message::has_media", skip_all)]
+    pub async fn has_media(
+        db: &MySqlPool,
+        uid: i32,
+        hash: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        // Only hex digits, nothing to escape.
+        if !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(false);
+        }
+
+        get_connection!(db);
+
+        let sql_raw = format!(
+            "SELECT COUNT(*) FROM `tbl_msg` INNER JOIN `tbl_chat` ON `tbl_chat`.`chat_id` = `tbl_msg`.`msg_chat_id` WHERE `tbl_chat`.`chat_user_id` = {} AND `tbl_msg`.`msg_medias` LIKE '%/media/{}\"%'",
+            uid, hash
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        let (count,): (i64,) = sqlx::query_as(&sql_raw).fetch_one(&mut connection).await?;
+        Ok(count > 0)
+    }
+
+    /// Full-text search over messages in chats of user `uid`, best matches
+    /// first.
+    ///
+    /// Needs a FULLTEXT index on `msg_content`:
+    ///
+    /// ```sql
+    /// ALTER TABLE `tbl_msg` ADD FULLTEXT INDEX `ft_msg_content` (`msg_content`);
+    /// ```
+    #[tracing::instrument(name = "Message::search", skip_all)]
+    pub async fn search(
+        db: &MySqlPool,
+        uid: i32,
+        search: &MessageSearch,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let against = format!(
+            "MATCH(`tbl_msg`.`msg_content`) AGAINST('{}' IN NATURAL LANGUAGE MODE)",
+            escape_string(&search.query)
+        );
+        let mut conditions = vec![
+            format!("`tbl_chat`.`chat_user_id` = {}", uid),
+            against.clone(),
+        ];
+        if let Some(model) = search.model {
+            conditions.push(format!(
+                "`tbl_msg`.`msg_model` = {}",
+                Into::<i8>::into(model)
+            ));
+        }
+        if let Some(sender) = search.sender.clone() {
+            conditions.push(format!(
+                "`tbl_msg`.`msg_sender` = {}",
+                Into::<i8>::into(sender)
+            ));
+        }
+        if let Some(from) = search.from {
+            conditions.push(format!(
+                "`tbl_msg`.`msg_created_at` >= '{}'",
+                from.format("%F")
+            ));
+        }
+        if let Some(to) = search.to.and_then(|to| to.succ_opt()) {
+            conditions.push(format!(
+                "`tbl_msg`.`msg_created_at` < '{}'",
+                to.format("%F")
+            ));
+        }
+
+        let sql_raw = format!(
+            "SELECT `tbl_msg`.* FROM `tbl_msg` INNER JOIN `tbl_chat` ON `tbl_chat`.`chat_id` = `tbl_msg`.`msg_chat_id` WHERE {} ORDER BY {} DESC LIMIT {} OFFSET {}",
+            conditions.join(" AND "),
+            against,
+            search.limit,
+            search.offset
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw).fetch_all(&mut connection).await?)
+    }
+
+    /// Save medias of an EXISTING message into database.
+    #[tracing::instrument(name = "Message::update_medias", skip_all)]
+    pub async fn update_medias(&self, db: &MySqlPool) -> Result<(), Box<dyn std::error::Error>> {
+        let msg_id = self.msg_id.ok_or("Message not saved to database yet")?;
+
+        get_connection!(db);
+
+        let medias = match self.msg_medias.as_ref() {
+            Some(medias) => format!("'{}'", escape_string(&serde_json::to_string(medias)?)),
+            None => "NULL".to_string(),
+        };
+        let query_string = format!(
+            "UPDATE `tbl_msg` SET `tbl_msg`.`msg_medias` = {} WHERE `tbl_msg`.`msg_id` = {}",
+            medias, msg_id
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
+
+        sqlx::query(&query_string).execute(&mut connection).await?;
+        Ok(())
+    }
 }
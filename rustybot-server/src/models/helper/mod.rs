@@ -1,9 +1,7 @@
 pub mod auth;
 pub mod chat;
 pub mod message;
+pub mod quota;
+pub mod share;
 pub mod user;
 
-pub use auth::*;
-pub use chat::*;
-pub use message::*;
-pub use user::*;
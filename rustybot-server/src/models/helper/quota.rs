@@ -0,0 +1,72 @@
+use crate::{
+    models::{Quota, QuotaType, Store},
+    utils::logging::redact_sql,
+};
+use rustybot_macros::get_connection;
+use sqlx::MySqlPool;
+
+impl Quota {
+    /// Units left in this quota, never negative.
+    pub fn remaining(&self) -> i32 {
+        (self.quota_total - self.quota_used).max(0)
+    }
+}
+
+/// Methods that implement SQL operations.
+impl Quota {
+    /// Query the quota of given type granted to a user.
+    #[tracing::instrument(name = "Quota::find", skip_all)]
+    pub async fn find(
+        db: &MySqlPool,
+        uid: i32,
+        ty: QuotaType,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let sql_raw = format!(
+            "SELECT * FROM `tbl_quota` WHERE `tbl_quota`.`quota_user_id` = {} AND `tbl_quota`.`quota_type` = {}",
+            uid,
+            Into::<i8>::into(ty)
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw)
+            .fetch_optional(&mut connection)
+            .await?)
+    }
+
+    /// Whether a user can still spend `amount` units of given quota type.
+    ///
+    /// Users without a quota row of that type are not limited.
+    pub async fn check(
+        store: &Store,
+        uid: i32,
+        ty: QuotaType,
+        amount: i32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(match store.quotas.find(uid, ty).await? {
+            Some(quota) => quota.remaining() >= amount,
+            None => true,
+        })
+    }
+
+    /// Record `amount` units of given quota type as used.
+    #[tracing::instrument(name = "Quota::consume", skip_all)]
+    pub async fn consume(
+        db: &MySqlPool,
+        uid: i32,
+        ty: QuotaType,
+        amount: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let sql_raw = format!(
+            "UPDATE `tbl_quota` SET `tbl_quota`.`quota_used` = `tbl_quota`.`quota_used` + {} WHERE `tbl_quota`.`quota_user_id` = {} AND `tbl_quota`.`quota_type` = {}",
+            amount,
+            uid,
+            Into::<i8>::into(ty)
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        sqlx::query(&sql_raw).execute(&mut connection).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use rustybot_macros::get_connection;
+use sqlx::{Acquire, MySqlPool};
+
+use crate::{models::Share, utils::logging::redact_sql};
+
+impl Share {
+    /// Create a new share of chat `cid` up to message `leaf`, with a random
+    /// unguessable token.
+    pub fn new(cid: i32, leaf: i32, expires_at: Option<DateTime<Utc>>) -> Self {
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let mut buf = [0u8; 64];
+        Self {
+            share_id: None,
+            share_chat_id: cid,
+            share_token: base16ct::lower::encode_str(&token, &mut buf)
+                .unwrap()
+                .to_string(),
+            share_leaf_msg_id: leaf,
+            share_created_at: Utc::now(),
+            share_expires_at: expires_at,
+            share_revoked: false,
+        }
+    }
+
+    /// Whether the share can still be viewed.
+    pub fn is_active(&self) -> bool {
+        !self.share_revoked
+            && self
+                .share_expires_at
+                .is_none_or(|expires_at| expires_at > Utc::now())
+    }
+
+    /// Whether `token` looks like a share token, before it gets near SQL.
+    pub fn is_token(token: &str) -> bool {
+        token.len() == 64 && token.chars().all(|c| c.is_ascii_hexdigit())
+    }
+}
+
+/// Methods that implement SQL operations.
+impl Share {
+    /// Save NEW share into database.
+    #[tracing::instrument(name = "Share::save", skip_all)]
+    pub async fn save(&self, db: &MySqlPool) -> Result<Self, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let mut key_pairs: Vec<&str> = vec![];
+        let mut value_pairs: Vec<String> = vec![];
+
+        key_pairs.push("`share_chat_id`");
+        value_pairs.push(format!("{}", self.share_chat_id));
+
+        key_pairs.push("`share_token`");
+        value_pairs.push(format!("'{}'", self.share_token));
+
+        key_pairs.push("`share_leaf_msg_id`");
+        value_pairs.push(format!("{}", self.share_leaf_msg_id));
+
+        key_pairs.push("`share_created_at`");
+        value_pairs.push(format!("'{}'", self.share_created_at.format("%+")));
+
+        if let Some(expires_at) = self.share_expires_at {
+            key_pairs.push("`share_expires_at`");
+            value_pairs.push(format!("'{}'", expires_at.format("%+")));
+        }
+
+        key_pairs.push("`share_revoked`");
+        value_pairs.push(format!("{}", self.share_revoked));
+
+        let query_string = format!(
+            "INSERT INTO `tbl_share` ({}) VALUES ({})",
+            key_pairs.join(", "),
+            value_pairs.join(", ")
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
+
+        let mut trans = connection.begin().await?;
+        sqlx::query(&query_string).execute(&mut trans).await?;
+        let share: Self = sqlx::query_as(
+            "SELECT * FROM `tbl_share` WHERE `tbl_share`.`share_id`= LAST_INSERT_ID()",
+        )
+        .fetch_one(&mut trans)
+        .await?;
+        trans.commit().await?;
+        Ok(share)
+    }
+
+    #[tracing::instrument(name = "Share::find_by_token", skip_all)]
+    pub async fn find_by_token(
+        db: &MySqlPool,
+        token: &str,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !Self::is_token(token) {
+            return Ok(None);
+        }
+
+        get_connection!(db);
+
+        let sql_raw = format!(
+            "SELECT * FROM `tbl_share` WHERE `tbl_share`.`share_token` = '{}'",
+            token
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw)
+            .fetch_optional(&mut connection)
+            .await?)
+    }
+
+    #[tracing::instrument(name = "Share::find_by_chat", skip_all)]
+    pub async fn find_by_chat(
+        db: &MySqlPool,
+        cid: i32,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        get_connection!(db);
+
+        let sql_raw = format!(
+            "SELECT * FROM `tbl_share` WHERE `tbl_share`.`share_chat_id` = {} ORDER BY `tbl_share`.`share_id`",
+            cid
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&sql_raw));
+        Ok(sqlx::query_as(&sql_raw).fetch_all(&mut connection).await?)
+    }
+
+    /// Revoke an EXISTING share, for good.
+    #[tracing::instrument(name = "Share::revoke", skip_all)]
+    pub async fn revoke(&mut self, db: &MySqlPool) -> Result<(), Box<dyn std::error::Error>> {
+        let share_id = self.share_id.ok_or("Share not saved to database yet")?;
+
+        get_connection!(db);
+
+        let query_string = format!(
+            "UPDATE `tbl_share` SET `tbl_share`.`share_revoked` = TRUE WHERE `tbl_share`.`share_id` = {}",
+            share_id
+        );
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
+
+        sqlx::query(&query_string).execute(&mut connection).await?;
+        self.share_revoked = true;
+        Ok(())
+    }
+}
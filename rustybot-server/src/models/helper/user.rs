@@ -1,33 +1,41 @@
 use crate::{
     models::{Auth, User, UserRole, UserState},
-    utils::sql::check_sql_component,
+    utils::{logging::redact_sql, sql::check_sql_component},
 };
 use chrono::{DateTime, Utc};
 use rustybot_macros::get_connection;
-use sqlx::Acquire;
+use sqlx::{Acquire, MySqlPool};
 
 /// Helper functions for managing user.
 ///
 /// Internally uses SQL to create/update/query users.
 impl User {
     /// Query user entity by its `user_name` field.
-    pub async fn find_by_name(id: &str) -> Result<Option<User>, Box<dyn std::error::Error>> {
-        if let Ok(id) = check_sql_component(id) {
-            get_connection!();
-            Ok(
-                sqlx::query_as("SELECT * FROM `tbl_user` WHERE `tbl_user`.`user_name` = ?")
-                    .bind(id)
-                    .fetch_optional(&mut connection)
-                    .await
-                    .unwrap(),
-            )
-        } else {
-            Err(format!("Invalid SQL component `{id}`").into())
-        }
+    #[tracing::instrument(name = "User::find_by_name", skip_all)]
+    pub async fn find_by_name(
+        db: &MySqlPool,
+        id: &str,
+    ) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        // Checked apart so that no error is held across queries.
+        let Ok(id) = check_sql_component(id) else {
+            return Err(format!("Invalid SQL component `{id}`").into());
+        };
+        get_connection!(db);
+        Ok(
+            sqlx::query_as("SELECT * FROM `tbl_user` WHERE `tbl_user`.`user_name` = ?")
+                .bind(id)
+                .fetch_optional(&mut connection)
+                .await
+                .unwrap(),
+        )
     }
     /// Query user entity by its `user_id` field.
-    pub async fn find_by_id(id: i32) -> Result<Option<User>, Box<dyn std::error::Error>> {
-        get_connection!();
+    #[tracing::instrument(name = "User::find_by_id", skip_all)]
+    pub async fn find_by_id(
+        db: &MySqlPool,
+        id: i32,
+    ) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        get_connection!(db);
         Ok(
             sqlx::query_as("SELECT * FROM `tbl_user` WHERE `tbl_user`.`user_id` = ?")
                 .bind(id)
@@ -37,34 +45,34 @@ impl User {
         )
     }
 
-    pub async fn auth(&self) -> Auth {
-        get_connection!();
-        sqlx::query_as("SELECT * FROM `tbl_auth` WHERE `auth_user_id` = ?;")
-            .bind(self.user_id)
-            .fetch_one(&mut connection)
-            .await
-            .unwrap()
+    #[tracing::instrument(name = "User::auth", skip_all)]
+    pub async fn auth(&self, db: &MySqlPool) -> Result<Auth, Box<dyn std::error::Error>> {
+        get_connection!(db);
+        Ok(
+            sqlx::query_as("SELECT * FROM `tbl_auth` WHERE `auth_user_id` = ?;")
+                .bind(self.user_id)
+                .fetch_one(&mut connection)
+                .await?,
+        )
     }
 
     /// Save any updates to current user entity. Only Ok(true) indicates a
     /// successful update operation. Ok(false) means no need to update.
-    pub async fn save(&self) -> Result<bool, Box<dyn std::error::Error>> {
+    #[tracing::instrument(name = "User::save", skip_all)]
+    pub async fn save(&self, db: &MySqlPool) -> Result<bool, Box<dyn std::error::Error>> {
         if self.user_id.is_none() {
             return Err("User ID not ready. Query from DB first.".into());
         }
 
-        get_connection!();
+        get_connection!(db);
 
-        if self.__content_updated == false {
+        if !self.__content_updated {
             return Ok(false);
         }
         let mut value_pairs: Vec<String> = vec![];
 
-        if self.user_avatar.is_some() {
-            value_pairs.push(format!(
-                "`tbl_user`.`user_avatar` = '{}'",
-                self.user_avatar.as_ref().unwrap()
-            ));
+        if let Some(avatar) = self.user_avatar.as_ref() {
+            value_pairs.push(format!("`tbl_user`.`user_avatar` = '{}'", avatar));
         }
 
         value_pairs.push(format!(
@@ -87,7 +95,7 @@ impl User {
             value_pairs.join(", ")
         );
 
-        log::debug!(target: "sql", "{}", query_string);
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
 
         sqlx::query(&query_string)
             .bind(self.user_id)
@@ -102,19 +110,20 @@ impl User {
     ///
     /// A new [`User`] instance embedded in the return value contains the latest
     /// `user_id`. So you should always replace existing one.
-    pub async fn create(&mut self) -> Result<User, Box<dyn std::error::Error>> {
+    #[tracing::instrument(name = "User::create", skip_all)]
+    pub async fn create(&mut self, db: &MySqlPool) -> Result<User, Box<dyn std::error::Error>> {
         if self.user_id.is_some() {
             return Err("User already exists in database, do NOT create again!".into());
         }
 
-        get_connection!();
+        get_connection!(db);
 
         let mut key_pairs: Vec<&str> = vec![];
         let mut value_pairs: Vec<String> = vec![];
 
-        if self.user_avatar.is_some() {
+        if let Some(avatar) = self.user_avatar.as_ref() {
             key_pairs.push("`user_avatar`");
-            value_pairs.push(format!("'{}'", self.user_avatar.as_ref().unwrap()));
+            value_pairs.push(format!("'{}'", avatar));
         }
 
         key_pairs.push("`user_name`");
@@ -135,7 +144,7 @@ impl User {
             value_pairs.join(", ")
         );
 
-        log::debug!(target: "sql", "{}", query_string);
+        log::debug!(target: "sql", "{}", redact_sql(&query_string));
 
         let mut trans = connection.begin().await.unwrap();
 
@@ -152,12 +161,9 @@ impl User {
 
         trans.commit().await.unwrap();
 
-        Auth::new(
-            user.user_id.clone().unwrap(),
-            &uuid::Uuid::new_v4().to_string(),
-        )
-        .create()
-        .await;
+        Auth::new(user.user_id.unwrap(), &uuid::Uuid::new_v4().to_string())
+            .create(db)
+            .await?;
 
         Ok(user)
     }
@@ -192,7 +198,7 @@ impl User {
     }
 
     pub fn id(&self) -> Option<i32> {
-        self.user_id.clone()
+        self.user_id
     }
 
     pub fn name(&self) -> String {
@@ -216,19 +222,19 @@ impl User {
     }
 
     pub fn created_at(&self) -> DateTime<Utc> {
-        self.user_created_at.clone()
+        self.user_created_at
     }
 
     pub fn active_at(&self) -> DateTime<Utc> {
-        self.user_active_at.clone()
+        self.user_active_at
     }
 
-    pub async fn auth_key(&self) -> String {
-        if self.__auth_key.is_some() {
-            return self.__auth_key.clone().unwrap();
-        } else if self.__auth_key.is_none() && self.user_id.is_some() {
+    pub async fn auth_key(&self, db: &MySqlPool) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(auth_key) = self.__auth_key.clone() {
+            Ok(auth_key)
+        } else if self.user_id.is_some() {
             // Query from database.
-            return self.auth().await.auth_key;
+            Ok(self.auth(db).await?.auth_key)
         } else {
             panic!("Auth key unavailable to new user not saved to database")
         }
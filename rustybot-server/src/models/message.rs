@@ -1,7 +1,17 @@
+use lazy_static::lazy_static;
+use regex::Regex;
 use rust_ai::openai::Model;
 use std::collections::HashMap;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::types::chat::{ContentPart, MessageContent};
+
+lazy_static! {
+    /// `${{name}}` placeholder of a media in message content.
+    static ref MEDIA_PLACEHOLDER: Regex = Regex::new(r"\$\{\{([^}]+)\}\}").unwrap();
+}
+
 #[derive(sqlx::FromRow, Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Message {
     pub msg_id: Option<i32>,
@@ -11,16 +21,87 @@ pub struct Message {
     pub msg_content: String,
     pub msg_medias: Option<sqlx::types::Json<HashMap<String, sqlx::types::Json<MessageMedia>>>>,
     pub msg_created_at: DateTime<Utc>,
+
+    /// Message this one follows, `None` for the first message of a chat.
+    /// Messages sharing a parent are alternative versions (branches).
+    ///
+    /// Added to existing databases, and chats saved before branching existed
+    /// linked up, with:
+    ///
+    /// ```sql
+    /// ALTER TABLE `tbl_msg` ADD COLUMN `msg_parent_id` INT NULL,
+    ///     ADD INDEX `idx_msg_parent_id` (`msg_parent_id`);
+    /// UPDATE `tbl_msg` m SET `msg_parent_id` = (
+    ///     SELECT MAX(p.`msg_id`) FROM (SELECT * FROM `tbl_msg`) p
+    ///     WHERE p.`msg_chat_id` = m.`msg_chat_id` AND p.`msg_id` < m.`msg_id`
+    /// );
+    /// ```
+    #[sqlx(default)]
+    pub msg_parent_id: Option<i32>,
 }
 
 impl Message {
+    /// URLs of the message medias.
+    pub fn media_urls(&self) -> impl Iterator<Item = &str> {
+        self.msg_medias
+            .iter()
+            .flat_map(|medias| medias.values())
+            .map(|media| media.url.as_str())
+    }
+
     /// Insert message medias into it
     pub fn message(&self) -> String {
+        self.render(&self.msg_content)
+    }
+
+    /// Message content for upstream. When the model supports vision, image
+    /// medias become `image_url` parts instead of markdown.
+    pub fn content(&self, vision: bool) -> MessageContent {
+        let Some(medias) = self.msg_medias.as_ref().filter(|_| vision) else {
+            return MessageContent::Text(self.message());
+        };
+
+        let mut parts = vec![];
+        let mut last = 0;
+        let push_text = |parts: &mut Vec<ContentPart>, text: &str| {
+            let text = self.render(text);
+            if !text.trim().is_empty() {
+                parts.push(ContentPart::Text {
+                    text: text.trim().to_string(),
+                });
+            }
+        };
+        for placeholder in MEDIA_PLACEHOLDER.captures_iter(&self.msg_content) {
+            let Some(media) = medias.get(&placeholder[1]) else {
+                continue;
+            };
+            if !matches!(media.ty, MediaType::Image) {
+                continue;
+            }
+            let whole = placeholder.get(0).unwrap();
+            push_text(&mut parts, &self.msg_content[last..whole.start()]);
+            parts.push(ContentPart::image(&media.url));
+            last = whole.end();
+        }
+        push_text(&mut parts, &self.msg_content[last..]);
+
+        if parts
+            .iter()
+            .all(|part| matches!(part, ContentPart::Text { .. }))
+        {
+            MessageContent::Text(self.message())
+        } else {
+            MessageContent::Parts(parts)
+        }
+    }
+
+    /// Insert message medias into `content`.
+    fn render(&self, content: &str) -> String {
         let updated_message = if self.msg_medias.is_none() {
-            self.msg_content.clone()
+            content.to_string()
         } else {
             let medias = self.msg_medias.clone().unwrap();
-            let mut raw_msg = self.msg_content.clone();
+            let mut raw_msg = content.to_string();
             for (name, media) in medias.iter() {
                 let key = format!("${{{{{}}}}}", name);
                 if raw_msg.contains(&key) {
@@ -34,6 +115,21 @@ impl Message {
     }
 }
 
+/// Criteria of a full-text search over messages.
+#[derive(Debug, Clone)]
+pub struct MessageSearch {
+    /// Words to look for, in MySQL natural language mode.
+    pub query: String,
+    pub model: Option<MessageModel>,
+    pub sender: Option<MessageSender>,
+    /// First day included.
+    pub from: Option<NaiveDate>,
+    /// Last day included.
+    pub to: Option<NaiveDate>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy)]
 pub enum MessageModel {
@@ -75,9 +171,23 @@ impl From<Model> for MessageModel {
     }
 }
 
-impl Into<i8> for MessageModel {
-    fn into(self) -> i8 {
-        match self {
+impl From<&str> for MessageModel {
+    fn from(value: &str) -> Self {
+        match value {
+            "gpt-4" => Self::GPT_4,
+            "gpt-4-0314" => Self::GPT_4_0314,
+            "gpt-4-32k" => Self::GPT_4_32K,
+            "gpt-4-32k-0314" => Self::GPT_4_32K_0314,
+            "gpt-3.5-turbo" => Self::GPT_3_5_Turbo,
+            "gpt-3.5-turbo-0301" => Self::GPT_3_5_Turbo_0301,
+            _ => Self::Others,
+        }
+    }
+}
+
+impl From<MessageModel> for i8 {
+    fn from(value: MessageModel) -> Self {
+        match value {
             MessageModel::GPT_3_5_Turbo => 0,
             MessageModel::GPT_4 => 1,
             MessageModel::GPT_4_32K => 2,
@@ -166,6 +276,17 @@ pub enum MessageSender {
     System,
 }
 
+impl MessageSender {
+    /// Role of the sender in OpenAI chat messages.
+    pub fn role(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Assistant => "assistant",
+            Self::System => "system",
+        }
+    }
+}
+
 impl From<i8> for MessageSender {
     fn from(value: i8) -> Self {
         match value {
@@ -177,9 +298,9 @@ impl From<i8> for MessageSender {
     }
 }
 
-impl Into<i8> for MessageSender {
-    fn into(self) -> i8 {
-        match self {
+impl From<MessageSender> for i8 {
+    fn from(value: MessageSender) -> Self {
+        match value {
             MessageSender::User => 0,
             MessageSender::Assistant => 1,
             MessageSender::System => 2,
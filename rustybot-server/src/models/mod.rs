@@ -1,12 +1,16 @@
 pub mod auth;
 pub mod chat;
+pub mod helper;
 pub mod message;
 pub mod quota;
+pub mod repo;
+pub mod share;
 pub mod user;
-pub mod helper;
 
 pub use auth::*;
 pub use chat::*;
 pub use message::*;
 pub use quota::*;
+pub use repo::Store;
+pub use share::*;
 pub use user::*;
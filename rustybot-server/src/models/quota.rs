@@ -12,6 +12,7 @@ pub enum QuotaType {
     ChatCompletion,
     ImageGeneration,
     TextToSpeech,
+    Transcription,
 }
 
 impl From<i8> for QuotaType {
@@ -20,6 +21,7 @@ impl From<i8> for QuotaType {
             0 => Self::ChatCompletion,
             1 => Self::ImageGeneration,
             2 => Self::TextToSpeech,
+            3 => Self::Transcription,
             _ => panic!("Impossible quota type value `{value}`"),
         }
     }
@@ -52,6 +54,7 @@ impl serde::Serialize for QuotaType {
             QuotaType::ChatCompletion => serializer.serialize_i8(0),
             QuotaType::ImageGeneration => serializer.serialize_i8(1),
             QuotaType::TextToSpeech => serializer.serialize_i8(2),
+            QuotaType::Transcription => serializer.serialize_i8(3),
         }
     }
 }
@@ -62,7 +65,7 @@ impl<'de> serde::de::Visitor<'de> for QuotaTypeVisitor {
     type Value = QuotaType;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("Acceptable values: 0, 1, 2")
+        formatter.write_str("Acceptable values: 0, 1, 2, 3")
     }
 
     fn visit_i8<E>(self, v: i8) -> Result<Self::Value, E>
@@ -73,6 +76,7 @@ impl<'de> serde::de::Visitor<'de> for QuotaTypeVisitor {
             0 => Ok(QuotaType::ChatCompletion),
             1 => Ok(QuotaType::ImageGeneration),
             2 => Ok(QuotaType::TextToSpeech),
+            3 => Ok(QuotaType::Transcription),
             _ => Err(E::custom(format!("Unsupported quota type `{v}`"))),
         }
     }
@@ -86,3 +90,14 @@ impl<'de> serde::Deserialize<'de> for QuotaType {
         deserializer.deserialize_i8(QuotaTypeVisitor)
     }
 }
+
+impl From<QuotaType> for i8 {
+    fn from(value: QuotaType) -> Self {
+        match value {
+            QuotaType::ChatCompletion => 0,
+            QuotaType::ImageGeneration => 1,
+            QuotaType::TextToSpeech => 2,
+            QuotaType::Transcription => 3,
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use async_trait::async_trait;
+
+use super::{AuthRepo, ChatRepo, MessageRepo, QuotaRepo, ShareRepo, UserRepo};
+use crate::models::{
+    Auth, Chat, Message, MessageSearch, MessageSender, Quota, QuotaType, Share, User,
+};
+
+/// Repositories keeping everything in memory, for tests and trying the server
+/// out without a database. IDs are given in order from 1 like MySQL does, and
+/// methods behave like those of [`super::MySqlRepo`] unless they tell
+/// otherwise.
+#[derive(Default)]
+pub struct MemoryRepo {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: Vec<User>,
+    auths: Vec<Auth>,
+    chats: Vec<Chat>,
+    messages: Vec<Message>,
+    quotas: Vec<Quota>,
+    shares: Vec<Share>,
+}
+
+impl MemoryRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grant user `uid` a total of `total` units of given quota type, keeping
+    /// what was used already.
+    pub fn set_quota(&self, uid: i32, ty: QuotaType, total: i32) {
+        let mut state = self.state.lock().unwrap();
+        let ty_value: i8 = ty.clone().into();
+        match state.quotas.iter_mut().find(|quota| {
+            quota.quota_user_id == uid && i8::from(quota.quota_type.clone()) == ty_value
+        }) {
+            Some(quota) => quota.quota_total = total,
+            None => {
+                let quota_id = state.quotas.len() as i32 + 1;
+                state.quotas.push(Quota {
+                    quota_id,
+                    quota_user_id: uid,
+                    quota_type: ty,
+                    quota_total: total,
+                    quota_used: 0,
+                });
+            }
+        }
+    }
+}
+
+/// Shortest word indexed by InnoDB FULLTEXT indexes by default
+/// (`innodb_ft_min_token_size`).
+const MIN_WORD_LEN: usize = 3;
+
+fn same_sender(a: &MessageSender, b: &MessageSender) -> bool {
+    i8::from(a.clone()) == i8::from(b.clone())
+}
+
+/// Lowercase words of `text` a FULLTEXT index would keep.
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| word.chars().count() >= MIN_WORD_LEN)
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+#[async_trait]
+impl UserRepo for MemoryRepo {
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .iter()
+            .find(|user| user.user_name == name)
+            .cloned())
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .iter()
+            .find(|user| user.user_id == Some(id))
+            .cloned())
+    }
+
+    async fn create(&self, user: &User) -> Result<User, Box<dyn std::error::Error>> {
+        if user.user_id.is_some() {
+            return Err("User already exists in database, do NOT create again!".into());
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.users.iter().any(|u| u.user_name == user.user_name) {
+            return Err(format!("User `{}` already exists", user.user_name).into());
+        }
+
+        let user_id = state.users.len() as i32 + 1;
+        let user = User {
+            user_id: Some(user_id),
+            __content_updated: false,
+            __auth_key: None,
+            ..user.clone()
+        };
+        state.users.push(user.clone());
+        let mut auth = Auth::new(user_id, &uuid::Uuid::new_v4().to_string());
+        auth.auth_id = Some(state.auths.len() as i32 + 1);
+        state.auths.push(auth);
+        Ok(user)
+    }
+
+    async fn save(&self, user: &User) -> Result<bool, Box<dyn std::error::Error>> {
+        if user.user_id.is_none() {
+            return Err("User ID not ready. Query from DB first.".into());
+        }
+        if !user.__content_updated {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().unwrap();
+        let saved = state
+            .users
+            .iter_mut()
+            .find(|u| u.user_id == user.user_id)
+            .ok_or("User not found")?;
+        *saved = User {
+            __content_updated: false,
+            ..user.clone()
+        };
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl AuthRepo for MemoryRepo {
+    async fn find_by_name(&self, name: &str) -> Result<Option<Auth>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        let Some(user_id) = state
+            .users
+            .iter()
+            .find(|user| user.user_name == name)
+            .and_then(|user| user.user_id)
+        else {
+            return Ok(None);
+        };
+        Ok(state
+            .auths
+            .iter()
+            .find(|auth| auth.auth_user_id == user_id)
+            .cloned())
+    }
+
+    async fn find_by_user(&self, user: &User) -> Result<Auth, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .auths
+            .iter()
+            .find(|auth| Some(auth.auth_user_id) == user.user_id)
+            .cloned()
+            .ok_or("No auth info of user")?)
+    }
+}
+
+#[async_trait]
+impl ChatRepo for MemoryRepo {
+    async fn save(&self, chat: &Chat) -> Result<Chat, Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        let chat = Chat {
+            chat_id: Some(state.chats.len() as i32 + 1),
+            ..chat.clone()
+        };
+        state.chats.push(chat.clone());
+        Ok(chat)
+    }
+
+    async fn set_leaf(&self, chat: &mut Chat, mid: i32) -> Result<(), Box<dyn std::error::Error>> {
+        let chat_id = chat.chat_id.ok_or("Chat not saved to database yet")?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(saved) = state.chats.iter_mut().find(|c| c.chat_id == Some(chat_id)) {
+            saved.chat_leaf_msg_id = Some(mid);
+        }
+        chat.chat_leaf_msg_id = Some(mid);
+        Ok(())
+    }
+
+    async fn find_by_user(&self, uid: i32) -> Result<Vec<Chat>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .chats
+            .iter()
+            .filter(|chat| chat.chat_user_id == uid)
+            .cloned()
+            .collect())
+    }
+
+    async fn find_by_id(&self, cid: i32) -> Result<Option<Chat>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .chats
+            .iter()
+            .find(|chat| chat.chat_id == Some(cid))
+            .cloned())
+    }
+}
+
+#[async_trait]
+impl MessageRepo for MemoryRepo {
+    async fn save(&self, message: &Message) -> Result<Message, Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        let message = Message {
+            msg_id: Some(state.messages.len() as i32 + 1),
+            ..message.clone()
+        };
+        state.messages.push(message.clone());
+        Ok(message)
+    }
+
+    async fn find_by_chat(&self, cid: i32) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .messages
+            .iter()
+            .filter(|msg| msg.msg_chat_id == cid)
+            .cloned()
+            .collect())
+    }
+
+    async fn find_by_id(&self, mid: i32) -> Result<Option<Message>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .messages
+            .iter()
+            .find(|msg| msg.msg_id == Some(mid))
+            .cloned())
+    }
+
+    async fn versions(
+        &self,
+        message: &Message,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .messages
+            .iter()
+            .filter(|msg| {
+                msg.msg_chat_id == message.msg_chat_id
+                    && msg.msg_parent_id == message.msg_parent_id
+                    && same_sender(&msg.msg_sender, &message.msg_sender)
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Messages containing any word of the query, ignoring case, the ones
+    /// with the most words first and then the newest.
+    ///
+    /// Stands in for the natural language FULLTEXT search of MySQL: whole
+    /// words are matched and words shorter than [`MIN_WORD_LEN`] ignored, as
+    /// with InnoDB defaults. Unlike MySQL, there are no stopwords and words
+    /// are not weighted by how rare they are, so ranking differs.
+    async fn search(
+        &self,
+        uid: i32,
+        search: &MessageSearch,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        let query = words(&search.query);
+        let mut found: Vec<(usize, &Message)> = state
+            .messages
+            .iter()
+            .filter(|msg| {
+                state
+                    .chats
+                    .iter()
+                    .any(|chat| chat.chat_id == Some(msg.msg_chat_id) && chat.chat_user_id == uid)
+            })
+            .filter(|msg| {
+                search
+                    .model
+                    .is_none_or(|model| i8::from(model) == i8::from(msg.msg_model))
+            })
+            .filter(|msg| {
+                search
+                    .sender
+                    .as_ref()
+                    .is_none_or(|sender| same_sender(sender, &msg.msg_sender))
+            })
+            .filter(|msg| {
+                let day = msg.msg_created_at.date_naive();
+                search.from.is_none_or(|from| day >= from) && search.to.is_none_or(|to| day <= to)
+            })
+            .filter_map(|msg| {
+                let content = words(&msg.msg_content);
+                let score = query.iter().filter(|word| content.contains(*word)).count();
+                (score > 0).then_some((score, msg))
+            })
+            .collect();
+        found.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.msg_id.cmp(&a.1.msg_id)));
+        Ok(found
+            .into_iter()
+            .skip(search.offset as usize)
+            .take(search.limit as usize)
+            .map(|(_, msg)| msg.clone())
+            .collect())
+    }
+
+    async fn update_medias(&self, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
+        let msg_id = message.msg_id.ok_or("Message not saved to database yet")?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(saved) = state
+            .messages
+            .iter_mut()
+            .find(|msg| msg.msg_id == Some(msg_id))
+        {
+            saved.msg_medias = message.msg_medias.clone();
+        }
+        Ok(())
+    }
+
+    async fn has_media(&self, uid: i32, hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        let suffix = format!("/media/{hash}");
+        Ok(state
+            .messages
+            .iter()
+            .filter(|msg| {
+                state
+                    .chats
+                    .iter()
+                    .any(|chat| chat.chat_id == Some(msg.msg_chat_id) && chat.chat_user_id == uid)
+            })
+            .flat_map(|msg| msg.msg_medias.iter().flat_map(|medias| medias.values()))
+            .any(|media| media.url.ends_with(&suffix)))
+    }
+}
+
+#[async_trait]
+impl QuotaRepo for MemoryRepo {
+    async fn find(
+        &self,
+        uid: i32,
+        ty: QuotaType,
+    ) -> Result<Option<Quota>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        let ty = i8::from(ty);
+        Ok(state
+            .quotas
+            .iter()
+            .find(|quota| quota.quota_user_id == uid && i8::from(quota.quota_type.clone()) == ty)
+            .cloned())
+    }
+
+    async fn consume(
+        &self,
+        uid: i32,
+        ty: QuotaType,
+        amount: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        let ty = i8::from(ty);
+        if let Some(quota) = state
+            .quotas
+            .iter_mut()
+            .find(|quota| quota.quota_user_id == uid && i8::from(quota.quota_type.clone()) == ty)
+        {
+            quota.quota_used += amount;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ShareRepo for MemoryRepo {
+    async fn save(&self, share: &Share) -> Result<Share, Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap();
+        // `share_token` is a unique key in MySQL.
+        if state
+            .shares
+            .iter()
+            .any(|saved| saved.share_token == share.share_token)
+        {
+            return Err("Share token already exists".into());
+        }
+        let share = Share {
+            share_id: Some(state.shares.len() as i32 + 1),
+            ..share.clone()
+        };
+        state.shares.push(share.clone());
+        Ok(share)
+    }
+
+    async fn find_by_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<Share>, Box<dyn std::error::Error>> {
+        if !Share::is_token(token) {
+            return Ok(None);
+        }
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .shares
+            .iter()
+            .find(|share| share.share_token == token)
+            .cloned())
+    }
+
+    async fn find_by_chat(&self, cid: i32) -> Result<Vec<Share>, Box<dyn std::error::Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .shares
+            .iter()
+            .filter(|share| share.share_chat_id == cid)
+            .cloned()
+            .collect())
+    }
+
+    async fn revoke(&self, share: &mut Share) -> Result<(), Box<dyn std::error::Error>> {
+        let share_id = share.share_id.ok_or("Share not saved to database yet")?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(saved) = state
+            .shares
+            .iter_mut()
+            .find(|s| s.share_id == Some(share_id))
+        {
+            saved.share_revoked = true;
+        }
+        share.share_revoked = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Storage of models behind traits, so handlers work the same against MySQL
+//! or against memory.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use sqlx::MySqlPool;
+
+use crate::models::{Auth, Chat, Message, MessageSearch, Quota, QuotaType, Share, User};
+
+pub mod memory;
+pub mod mysql;
+
+pub use memory::MemoryRepo;
+pub use mysql::MySqlRepo;
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    /// User by its login name.
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>, Box<dyn std::error::Error>>;
+
+    /// User by its ID.
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, Box<dyn std::error::Error>>;
+
+    /// Save a NEW user, together with a random auth key. The returned user
+    /// has its ID.
+    async fn create(&self, user: &User) -> Result<User, Box<dyn std::error::Error>>;
+
+    /// Save updates of an EXISTING user. Ok(false) means nothing to update.
+    async fn save(&self, user: &User) -> Result<bool, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+pub trait AuthRepo: Send + Sync {
+    /// Authentication information of the user with login name `name`.
+    async fn find_by_name(&self, name: &str) -> Result<Option<Auth>, Box<dyn std::error::Error>>;
+
+    /// Authentication information of an EXISTING user.
+    async fn find_by_user(&self, user: &User) -> Result<Auth, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+pub trait ChatRepo: Send + Sync {
+    /// Save a NEW chat, with its selected branch if set. The returned chat
+    /// has its ID.
+    async fn save(&self, chat: &Chat) -> Result<Chat, Box<dyn std::error::Error>>;
+
+    /// Save the last message of the selected branch of an EXISTING chat.
+    async fn set_leaf(&self, chat: &mut Chat, mid: i32) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn find_by_user(&self, uid: i32) -> Result<Vec<Chat>, Box<dyn std::error::Error>>;
+
+    async fn find_by_id(&self, cid: i32) -> Result<Option<Chat>, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+pub trait MessageRepo: Send + Sync {
+    /// Save a NEW message. The returned message has its ID.
+    async fn save(&self, message: &Message) -> Result<Message, Box<dyn std::error::Error>>;
+
+    /// Messages of chat `cid`, sorted by ID.
+    async fn find_by_chat(&self, cid: i32) -> Result<Vec<Message>, Box<dyn std::error::Error>>;
+
+    async fn find_by_id(&self, mid: i32) -> Result<Option<Message>, Box<dyn std::error::Error>>;
+
+    /// Every version of a message: messages of the same sender following the
+    /// same parent, itself included, oldest first.
+    async fn versions(&self, message: &Message)
+        -> Result<Vec<Message>, Box<dyn std::error::Error>>;
+
+    /// Messages in chats of user `uid` matching `search`, best matches first.
+    async fn search(
+        &self,
+        uid: i32,
+        search: &MessageSearch,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error>>;
+
+    /// Save medias of an EXISTING message.
+    async fn update_medias(&self, message: &Message) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Whether stored media `hash` is attached to a message in a chat of
+    /// user `uid`.
+    async fn has_media(&self, uid: i32, hash: &str) -> Result<bool, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+pub trait QuotaRepo: Send + Sync {
+    /// Quota of given type granted to a user.
+    async fn find(
+        &self,
+        uid: i32,
+        ty: QuotaType,
+    ) -> Result<Option<Quota>, Box<dyn std::error::Error>>;
+
+    /// Record `amount` units of given quota type as used.
+    async fn consume(
+        &self,
+        uid: i32,
+        ty: QuotaType,
+        amount: i32,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+pub trait ShareRepo: Send + Sync {
+    /// Save a NEW share. The returned share has its ID.
+    async fn save(&self, share: &Share) -> Result<Share, Box<dyn std::error::Error>>;
+
+    async fn find_by_token(&self, token: &str)
+        -> Result<Option<Share>, Box<dyn std::error::Error>>;
+
+    async fn find_by_chat(&self, cid: i32) -> Result<Vec<Share>, Box<dyn std::error::Error>>;
+
+    /// Revoke an EXISTING share, for good.
+    async fn revoke(&self, share: &mut Share) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Every repository the server needs, given to handlers as app data.
+#[derive(Clone)]
+pub struct Store {
+    pub users: Arc<dyn UserRepo>,
+    pub auths: Arc<dyn AuthRepo>,
+    pub chats: Arc<dyn ChatRepo>,
+    pub messages: Arc<dyn MessageRepo>,
+    pub quotas: Arc<dyn QuotaRepo>,
+    pub shares: Arc<dyn ShareRepo>,
+    pool: Option<MySqlPool>,
+}
+
+impl Store {
+    /// Store everything in the MySQL database behind `pool`.
+    pub fn mysql(pool: MySqlPool) -> Self {
+        let repo = Arc::new(MySqlRepo::new(pool.clone()));
+        Self {
+            users: repo.clone(),
+            auths: repo.clone(),
+            chats: repo.clone(),
+            messages: repo.clone(),
+            quotas: repo.clone(),
+            shares: repo,
+            pool: Some(pool),
+        }
+    }
+
+    /// Store everything in `repo`, lost when the process exits.
+    pub fn memory(repo: Arc<MemoryRepo>) -> Self {
+        Self {
+            users: repo.clone(),
+            auths: repo.clone(),
+            chats: repo.clone(),
+            messages: repo.clone(),
+            quotas: repo.clone(),
+            shares: repo,
+            pool: None,
+        }
+    }
+
+    /// Database pool, `None` when not backed by a database.
+    pub fn pool(&self) -> Option<&MySqlPool> {
+        self.pool.as_ref()
+    }
+
+    /// Close the database pool once pending queries are done.
+    pub async fn close(&self) {
+        if let Some(pool) = self.pool.as_ref() {
+            pool.close().await;
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use async_trait::async_trait;
+use sqlx::MySqlPool;
+
+use super::{AuthRepo, ChatRepo, MessageRepo, QuotaRepo, ShareRepo, UserRepo};
+use crate::models::{Auth, Chat, Message, MessageSearch, Quota, QuotaType, Share, User};
+
+/// Repositories backed by the SQL helpers of every model.
+pub struct MySqlRepo {
+    pool: MySqlPool,
+}
+
+impl MySqlRepo {
+    pub fn new(pool: MySqlPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepo for MySqlRepo {
+    async fn find_by_name(&self, name: &str) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        User::find_by_name(&self.pool, name).await
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, Box<dyn std::error::Error>> {
+        User::find_by_id(&self.pool, id).await
+    }
+
+    async fn create(&self, user: &User) -> Result<User, Box<dyn std::error::Error>> {
+        user.clone().create(&self.pool).await
+    }
+
+    async fn save(&self, user: &User) -> Result<bool, Box<dyn std::error::Error>> {
+        user.save(&self.pool).await
+    }
+}
+
+#[async_trait]
+impl AuthRepo for MySqlRepo {
+    async fn find_by_name(&self, name: &str) -> Result<Option<Auth>, Box<dyn std::error::Error>> {
+        Auth::auth(&self.pool, name).await
+    }
+
+    async fn find_by_user(&self, user: &User) -> Result<Auth, Box<dyn std::error::Error>> {
+        user.auth(&self.pool).await
+    }
+}
+
+#[async_trait]
+impl ChatRepo for MySqlRepo {
+    async fn save(&self, chat: &Chat) -> Result<Chat, Box<dyn std::error::Error>> {
+        chat.save(&self.pool).await
+    }
+
+    async fn set_leaf(&self, chat: &mut Chat, mid: i32) -> Result<(), Box<dyn std::error::Error>> {
+        chat.set_leaf(&self.pool, mid).await
+    }
+
+    async fn find_by_user(&self, uid: i32) -> Result<Vec<Chat>, Box<dyn std::error::Error>> {
+        Chat::find_chats_by_user(&self.pool, uid).await
+    }
+
+    async fn find_by_id(&self, cid: i32) -> Result<Option<Chat>, Box<dyn std::error::Error>> {
+        Chat::chat_by_id(&self.pool, cid).await
+    }
+}
+
+#[async_trait]
+impl MessageRepo for MySqlRepo {
+    async fn save(&self, message: &Message) -> Result<Message, Box<dyn std::error::Error>> {
+        message.save(&self.pool).await
+    }
+
+    async fn find_by_chat(&self, cid: i32) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        Message::find_messages_by_chat(&self.pool, cid).await
+    }
+
+    async fn find_by_id(&self, mid: i32) -> Result<Option<Message>, Box<dyn std::error::Error>> {
+        Message::find_by_id(&self.pool, mid).await
+    }
+
+    async fn versions(
+        &self,
+        message: &Message,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        message.versions(&self.pool).await
+    }
+
+    async fn search(
+        &self,
+        uid: i32,
+        search: &MessageSearch,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        Message::search(&self.pool, uid, search).await
+    }
+
+    async fn update_medias(&self, message: &Message) -> Result<(), Box<dyn std::error::Error>> {
+        message.update_medias(&self.pool).await
+    }
+
+    async fn has_media(&self, uid: i32, hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Message::has_media(&self.pool, uid, hash).await
+    }
+}
+
+#[async_trait]
+impl QuotaRepo for MySqlRepo {
+    async fn find(
+        &self,
+        uid: i32,
+        ty: QuotaType,
+    ) -> Result<Option<Quota>, Box<dyn std::error::Error>> {
+        Quota::find(&self.pool, uid, ty).await
+    }
+
+    async fn consume(
+        &self,
+        uid: i32,
+        ty: QuotaType,
+        amount: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Quota::consume(&self.pool, uid, ty, amount).await
+    }
+}
+
+#[async_trait]
+impl ShareRepo for MySqlRepo {
+    async fn save(&self, share: &Share) -> Result<Share, Box<dyn std::error::Error>> {
+        share.save(&self.pool).await
+    }
+
+    async fn find_by_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<Share>, Box<dyn std::error::Error>> {
+        Share::find_by_token(&self.pool, token).await
+    }
+
+    async fn find_by_chat(&self, cid: i32) -> Result<Vec<Share>, Box<dyn std::error::Error>> {
+        Share::find_by_chat(&self.pool, cid).await
+    }
+
+    async fn revoke(&self, share: &mut Share) -> Result<(), Box<dyn std::error::Error>> {
+        share.revoke(&self.pool).await
+    }
+}
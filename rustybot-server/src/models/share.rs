@@ -0,0 +1,31 @@
+use chrono::{DateTime, Utc};
+
+/// Public read-only snapshot of a chat.
+///
+/// Stored in its own table:
+///
+/// ```sql
+/// CREATE TABLE `tbl_share` (
+///     `share_id` INT NOT NULL AUTO_INCREMENT,
+///     `share_chat_id` INT NOT NULL,
+///     `share_token` CHAR(64) NOT NULL,
+///     `share_leaf_msg_id` INT NOT NULL,
+///     `share_created_at` DATETIME NOT NULL,
+///     `share_expires_at` DATETIME NULL,
+///     `share_revoked` BOOLEAN NOT NULL DEFAULT FALSE,
+///     PRIMARY KEY (`share_id`),
+///     UNIQUE KEY `uk_share_token` (`share_token`),
+///     KEY `idx_share_chat_id` (`share_chat_id`)
+/// );
+/// ```
+#[derive(sqlx::FromRow, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Share {
+    pub share_id: Option<i32>,
+    pub share_chat_id: i32,
+    pub share_token: String,
+    /// Last message of the shared branch, later messages are not shared.
+    pub share_leaf_msg_id: i32,
+    pub share_created_at: DateTime<Utc>,
+    pub share_expires_at: Option<DateTime<Utc>>,
+    pub share_revoked: bool,
+}
@@ -14,7 +14,7 @@ pub struct User {
 
     #[serde(skip)]
     pub(crate) __content_updated: bool,
-    
+
     #[serde(skip)]
     pub(crate) __auth_key: Option<String>,
 }
@@ -52,9 +52,9 @@ impl From<i8> for UserRole {
     }
 }
 
-impl Into<i8> for UserRole {
-    fn into(self) -> i8 {
-        match self {
+impl From<UserRole> for i8 {
+    fn from(value: UserRole) -> Self {
+        match value {
             UserRole::Normal => 0,
             UserRole::Admin => 1,
         }
@@ -127,9 +127,9 @@ pub enum UserState {
     Inactive,
 }
 
-impl Into<i8> for UserState {
-    fn into(self) -> i8 {
-        match self {
+impl From<UserState> for i8 {
+    fn from(value: UserState) -> Self {
+        match value {
             UserState::Active => 0,
             UserState::Inactive => 1,
         }
@@ -1,83 +1,484 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::header::ContentType, HttpResponseBuilder};
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use futures::StreamExt;
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, Method, RequestBuilder, StatusCode};
 use tokio::sync::mpsc::Sender;
 
-pub async fn post_remote_stream<T>(
-    endpoint: impl std::fmt::Display,
-    data: &T,
-    sender: Option<Sender<Bytes>>,
-) -> actix_web::HttpResponse
-where
-    T: serde::Serialize + ?Sized,
-{
-    let config = rust_ai::utils::config::Config::load().unwrap();
-    let url = format!("{}{}", config.openai.base_endpoint(), endpoint);
-    let mut req = reqwest::Client::new().post(url);
-    req = req.header("Authorization", format!("Bearer {}", config.openai.api_key));
-
-    let res = req.json(&data).send().await.unwrap();
-
-    let mut resp_builder = actix_web::HttpResponse::Ok();
-
-    for header in res.headers().iter() {
-        resp_builder.append_header(header);
+use crate::{
+    key_pool::{KeyHealth, KeyOutcome, KeyPool},
+    metrics::{self, ActiveStream},
+    types::error::ApiError,
+    utils::{
+        config::{Config, Upstream, UpstreamKey},
+        logging,
+    },
+};
+
+/// Headers describing the upstream connection or framing, which actix sets
+/// on its own. Never forwarded whatever the configuration says.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "content-length",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Shared HTTP client for talking to OpenAI.
+///
+/// One instance is created at startup and handed to every worker as app
+/// data, so connections are pooled across requests. Settings other than those
+/// of the connection pool can be swapped with [`RemoteClient::reconfigure()`]
+/// while requests are in flight.
+pub struct RemoteClient {
+    client: reqwest::Client,
+    base_endpoint: ArcSwap<String>,
+    keys: KeyPool,
+    settings: ArcSwap<Upstream>,
+}
+
+impl RemoteClient {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let settings = config.upstream.clone();
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(settings.connect_timeout))
+            .pool_idle_timeout(Duration::from_secs(settings.pool_idle_timeout))
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+            .tcp_keepalive(Duration::from_secs(60))
+            .build()?;
+
+        let keys = KeyPool::new(
+            upstream_keys(config),
+            Duration::from_secs(settings.key_cooldown),
+            Duration::from_secs(settings.key_exhausted_cooldown),
+        );
+
+        Ok(Self {
+            client,
+            base_endpoint: ArcSwap::from_pointee(base_endpoint(config)),
+            keys,
+            settings: ArcSwap::from_pointee(settings),
+        })
+    }
+
+    /// Apply the upstream settings of a reloaded configuration. The
+    /// connection pool is kept as is, changes to its settings need a restart.
+    pub fn reconfigure(&self, config: &Config) {
+        let settings = config.upstream.clone();
+        self.keys.reconfigure(
+            upstream_keys(config),
+            Duration::from_secs(settings.key_cooldown),
+            Duration::from_secs(settings.key_exhausted_cooldown),
+        );
+        self.base_endpoint.store(Arc::new(base_endpoint(config)));
+        self.settings.store(Arc::new(settings));
     }
 
-    let mut stream = res.bytes_stream();
+    fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.settings.load().read_timeout)
+    }
+
+    /// Delay before retry number `attempt` (starting from 0), honouring the
+    /// upstream `Retry-After` header when present.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let settings = self.settings.load();
+        let max = Duration::from_millis(settings.retry_max_delay);
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(max);
+        }
+        let ceiling = settings
+            .retry_base_delay
+            .saturating_mul(1u64 << attempt.min(16))
+            .min(settings.retry_max_delay);
+        // "Full jitter": pick anything between zero and the current ceiling.
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+    }
+
+    /// Send a request, retrying on 429/5xx responses and connection
+    /// errors. `build` adds the body to the request and is called again for
+    /// every attempt. Only the head of a successful response has been received
+    /// when this returns, so no byte has been forwarded to the client yet.
+    ///
+    /// A key that got rate limited, ran out of quota or was rejected is
+    /// reported to the pool and the request fails over to the next key right
+    /// away, without counting as a retry. With no other key left, a request
+    /// is only retried on a rate limited key.
+    #[tracing::instrument(skip(self, build), fields(attempts))]
+    async fn send(
+        &self,
+        method: Method,
+        endpoint: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+        let url = format!("{}{}", self.base_endpoint.load(), endpoint);
+        let mut attempt = 0;
+        let mut tried: Vec<String> = vec![];
+        loop {
+            let key = self
+                .keys
+                .pick(&tried)
+                .or_else(|| {
+                    tried.clear();
+                    self.keys.pick(&tried)
+                })
+                .ok_or("no upstream key configured")?;
 
-    resp_builder.streaming(async_stream::stream! {
-        while let Some(item) = stream.next().await{
-            let output = match item {
-                Ok(bytes) => {
-                    if let Some(sender) = sender.clone() {
-                        if let Err(e) = sender.send(bytes.clone()).await {
-                            log::error!(target: "app", "Error extracting bytes from stream: `{e}`");
-                        };
+            let mut req = build(
+                self.client
+                    .request(method.clone(), &url)
+                    .header("Authorization", format!("Bearer {}", key.api_key)),
+            );
+            if let Some(organization) = key.organization.as_ref() {
+                req = req.header("OpenAI-Organization", organization);
+            }
+            if let Some(request_id) = logging::request_id() {
+                req = req.header("X-Request-Id", request_id);
+            }
+
+            tracing::Span::current().record("attempts", attempt + 1);
+            let result = tokio::time::timeout(self.read_timeout(), req.send()).await;
+            let retries_left = attempt < self.settings.load().max_retries;
+
+            let retry_after = match result {
+                Ok(Ok(res)) => {
+                    let status = res.status();
+                    if !status.is_success() {
+                        metrics::upstream_error(endpoint, status.as_str());
                     }
-                    Ok(bytes)
-                },
-                Err(e) => {
-                    Err(e)
+                    let key_failure = is_key_failure(status);
+                    let can_failover = key_failure
+                        && self.keys.has_available(
+                            &[&tried[..], std::slice::from_ref(&key.api_key)].concat(),
+                        );
+                    let retry_after = parse_retry_after(&res);
+                    let (outcome, res) = self.key_outcome(res, retry_after).await;
+                    // Retrying with a key out of quota or rejected cannot help.
+                    let key_dead =
+                        matches!(outcome, KeyOutcome::QuotaExhausted | KeyOutcome::Rejected);
+                    self.keys.report(&key.api_key, outcome);
+                    if !(can_failover || (retries_left && is_retryable(status) && !key_dead)) {
+                        return Ok(res);
+                    }
+
+                    if can_failover {
+                        log::warn!(target: "openai", "Upstream `{endpoint}` responded `{status}`, failing over to next key");
+                        tried.push(key.api_key.clone());
+                        continue;
+                    }
+                    log::warn!(target: "openai", "Upstream `{endpoint}` responded `{status}`, retrying");
+                    retry_after
+                }
+                Ok(Err(e)) => {
+                    metrics::upstream_error(
+                        endpoint,
+                        if e.is_connect() { "connect" } else { "request" },
+                    );
+                    self.keys
+                        .report(&key.api_key, KeyOutcome::Failed(e.to_string()));
+                    if !retries_left || !(e.is_connect() || e.is_timeout()) {
+                        return Err(e.into());
+                    }
+                    log::warn!(target: "openai", "Request to upstream `{endpoint}` failed: `{e}`, retrying");
+                    None
+                }
+                Err(_) => {
+                    metrics::upstream_error(endpoint, "timeout");
+                    self.keys
+                        .report(&key.api_key, KeyOutcome::Failed("timed out".to_string()));
+                    if !retries_left {
+                        return Err("upstream response timed out".into());
+                    }
+                    log::warn!(target: "openai", "Upstream `{endpoint}` timed out, retrying");
+                    None
                 }
             };
-            yield output;
+
+            tokio::time::sleep(self.backoff(attempt, retry_after)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Tell what a response says about the key it was sent with. Error bodies
+    /// are read to tell an exhausted quota from a mere rate limit, the
+    /// response is then rebuilt from the buffered body.
+    async fn key_outcome(
+        &self,
+        res: reqwest::Response,
+        retry_after: Option<Duration>,
+    ) -> (KeyOutcome, reqwest::Response) {
+        let status = res.status();
+        if status.is_success() {
+            return (KeyOutcome::Success, res);
+        }
+
+        let headers = res.headers().clone();
+        let body = match tokio::time::timeout(self.read_timeout(), res.bytes()).await {
+            Ok(Ok(body)) => body,
+            _ => Bytes::new(),
+        };
+        let outcome = if String::from_utf8_lossy(&body).contains("insufficient_quota") {
+            KeyOutcome::QuotaExhausted
+        } else {
+            KeyOutcome::from_status(status, retry_after)
+        };
+        let mut rebuilt = http::Response::new(body);
+        *rebuilt.status_mut() = status;
+        *rebuilt.headers_mut() = headers;
+        (outcome, rebuilt.into())
+    }
+
+    /// Start our response from the upstream one: same status code, and only
+    /// the headers let through by the configured rules.
+    fn response_builder(&self, res: &reqwest::Response) -> HttpResponseBuilder {
+        let status = actix_web::http::StatusCode::from_u16(res.status().as_u16())
+            .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY);
+        let mut resp_builder = actix_web::HttpResponse::build(status);
+        let settings = self.settings.load();
+        for (name, value) in res.headers().iter() {
+            if HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                || !settings.forward_headers.allows(name.as_str())
+            {
+                continue;
+            }
+            resp_builder.append_header((name.as_str(), value.as_bytes()));
+        }
+        resp_builder
+    }
+
+    /// Respond with an upstream error, keeping its status but normalising the
+    /// body to the OpenAI error schema.
+    async fn upstream_error(
+        &self,
+        mut resp_builder: HttpResponseBuilder,
+        res: reqwest::Response,
+    ) -> actix_web::HttpResponse {
+        let status = actix_web::http::StatusCode::from_u16(res.status().as_u16())
+            .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY);
+        let body = match tokio::time::timeout(self.read_timeout(), res.bytes()).await {
+            Ok(Ok(body)) => body,
+            _ => Bytes::new(),
+        };
+        let error = ApiError::from_upstream(status, &body);
+        log::warn!(target: "openai", "Upstream responded `{status}`: {error}");
+        resp_builder
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&error).unwrap())
+    }
+
+    /// Health statistics of the upstream keys.
+    pub fn key_health(&self) -> Vec<KeyHealth> {
+        self.keys.health()
+    }
+
+    #[tracing::instrument(skip_all, fields(%endpoint))]
+    pub async fn post_remote_stream<T>(
+        &self,
+        endpoint: impl std::fmt::Display,
+        data: &T,
+        sender: Option<Sender<Bytes>>,
+    ) -> actix_web::HttpResponse
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let started = Instant::now();
+        let res = match self
+            .send(Method::POST, &endpoint.to_string(), |req| req.json(data))
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => return upstream_unavailable(e),
+        };
+
+        let mut resp_builder = self.response_builder(&res);
+        if !res.status().is_success() {
+            return self.upstream_error(resp_builder, res).await;
         }
 
-        if let Some(sender) = sender.clone() {
-            if let Err(e) = sender.send(Bytes::from(b"EOS__EOS".to_vec())).await {
+        let mut stream = res.bytes_stream();
+        let read_timeout = self.read_timeout();
+        let endpoint = endpoint.to_string();
+        // Held by the stream so that the span lasts until the stream ends.
+        let span = tracing::Span::current();
+
+        resp_builder.streaming(async_stream::stream! {
+            let _active = ActiveStream::start();
+            let mut first_byte = true;
+            loop {
+                let item = match tokio::time::timeout(read_timeout, stream.next()).await {
+                    Ok(Some(item)) => item.map_err(|e| e.to_string()),
+                    Ok(None) => break,
+                    Err(_) => Err(format!("upstream stream stalled for more than {:?}", read_timeout)),
+                };
+                match item {
+                    Ok(bytes) => {
+                        if first_byte {
+                            first_byte = false;
+                            // Entered rather than given as parent, which the
+                            // OpenTelemetry layer ignores.
+                            span.in_scope(|| {
+                                tracing::info!(ttfb_ms = started.elapsed().as_millis() as u64, "first byte from upstream")
+                            });
+                        }
+                        if let Some(sender) = sender.clone() {
+                            if let Err(e) = sender.send(bytes.clone()).await {
+                                log::error!(target: "app", "Error extracting bytes from stream: `{e}`");
+                            };
+                        }
+                        yield Ok::<_, std::io::Error>(bytes);
+                    },
+                    Err(e) => {
+                        // Headers are gone already, so the only way left to
+                        // tell the client is a last event in the stream.
+                        log::error!(target: "app", "Upstream stream broken: `{e}`");
+                        metrics::upstream_error(&endpoint, "stream");
+                        let error = ApiError::new("server_error", format!("Upstream stream broken: {e}"))
+                            .with_code("upstream_stream_broken");
+                        yield Ok(Bytes::from(error.to_sse()));
+                        break;
+                    }
+                };
+            }
+
+            if let Some(sender) = sender.clone() {
+                if let Err(e) = sender.send(Bytes::from(b"EOS__EOS".to_vec())).await {
+                    log::error!(target: "app", "Error extracting bytes from stream: `{e}`");
+                };
+            }
+        })
+    }
+
+    pub async fn post_remote<T>(
+        &self,
+        endpoint: impl std::fmt::Display,
+        data: &T,
+        sender: Option<Sender<Bytes>>,
+    ) -> actix_web::HttpResponse
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let (mut resp_builder, bytes) = match self
+            .call(Method::POST, &endpoint.to_string(), |req| req.json(data))
+            .await
+        {
+            Ok(result) => result,
+            Err(resp) => return resp,
+        };
+
+        if let Some(sender) = sender {
+            if let Err(e) = sender.send(bytes.clone()).await {
                 log::error!(target: "app", "Error extracting bytes from stream: `{e}`");
             };
         }
-    })
+
+        resp_builder.body(bytes)
+    }
+
+    /// Make a non-streaming request and read the whole response body.
+    ///
+    /// On success, returns a response builder mirroring the upstream response
+    /// together with its body, so the caller can inspect or rewrite it. Any
+    /// failure is already turned into an error response.
+    pub async fn call(
+        &self,
+        method: Method,
+        endpoint: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<(HttpResponseBuilder, Bytes), actix_web::HttpResponse> {
+        let res = self
+            .send(method, endpoint, build)
+            .await
+            .map_err(upstream_unavailable)?;
+
+        let resp_builder = self.response_builder(&res);
+        if !res.status().is_success() {
+            return Err(self.upstream_error(resp_builder, res).await);
+        }
+
+        match tokio::time::timeout(self.read_timeout(), res.bytes()).await {
+            Ok(Ok(bytes)) => Ok((resp_builder, bytes)),
+            Ok(Err(e)) => Err(upstream_unavailable(e.into())),
+            Err(_) => Err(upstream_unavailable("upstream response timed out".into())),
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Statuses caused by the key in use rather than by the request itself.
+fn is_key_failure(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
 }
 
-pub async fn post_remote<T>(
-    endpoint: impl std::fmt::Display,
-    data: &T,
-    sender: Option<Sender<Bytes>>,
-) -> actix_web::HttpResponse
-where
-    T: serde::Serialize + ?Sized,
-{
-    let config = rust_ai::utils::config::Config::load().unwrap();
-    let url = format!("{}{}", config.openai.base_endpoint(), endpoint);
-    let mut req = reqwest::Client::new().post(url);
-    req = req.header("Authorization", format!("Bearer {}", config.openai.api_key));
-
-    let res = req.json(&data).send().await.unwrap();
-
-    let mut resp_builder = actix_web::HttpResponse::Ok();
-    for header in res.headers().iter() {
-        resp_builder.append_header(header);
+impl KeyOutcome {
+    fn from_status(status: StatusCode, retry_after: Option<Duration>) -> Self {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited(retry_after),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Rejected,
+            status if status.is_server_error() => Self::Failed(status.to_string()),
+            _ => Self::Success,
+        }
+    }
+}
+
+/// Parse `Retry-After`, either as delta seconds or as an HTTP date.
+fn parse_retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
     }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
 
-    let bytes = res.bytes().await.unwrap();
+fn upstream_unavailable(e: Box<dyn std::error::Error>) -> actix_web::HttpResponse {
+    log::error!(target: "app", "Upstream request failed: `{e}`");
+    let timed_out = e.to_string().contains("timed out")
+        || e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout());
+    let status = if timed_out {
+        actix_web::http::StatusCode::GATEWAY_TIMEOUT
+    } else {
+        actix_web::http::StatusCode::BAD_GATEWAY
+    };
+    ApiError::new("server_error", format!("Upstream request failed: {e}"))
+        .with_code("upstream_unavailable")
+        .response(status)
+}
 
-    if let Some(sender) = sender {
-        if let Err(e) = sender.send(bytes.clone()).await {
-            log::error!(target: "app", "Error extracting bytes from stream: `{e}`");
-        };
+/// Keys to balance requests over, the single `openai.api_key` unless a pool
+/// is configured.
+fn upstream_keys(config: &Config) -> Vec<UpstreamKey> {
+    if config.upstream.keys.is_empty() {
+        vec![UpstreamKey {
+            api_key: config.openai.api_key.clone(),
+            organization: None,
+            weight: 1,
+        }]
+    } else {
+        config.upstream.keys.clone()
     }
+}
 
-    resp_builder.body(bytes)
+fn base_endpoint(config: &Config) -> String {
+    config
+        .openai
+        .base_endpoint
+        .trim_end_matches('/')
+        .to_string()
 }
@@ -0,0 +1,154 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    /// Markdown image, as rendered by `Message::message()`.
+    static ref MARKDOWN_IMAGE: Regex = Regex::new(r"!\[[^\]]*\]\(([^)\s]+)\)").unwrap();
+}
+
+/// Body of `/v1/chat/completions`.
+///
+/// Only the fields acted upon are typed, everything else is passed through to
+/// upstream untouched.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+
+    pub messages: Vec<ChatRequestMessage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChatRequestMessage {
+    pub role: String,
+
+    #[serde(default)]
+    pub content: MessageContent,
+
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Message content, either plain text or multi-part as used by vision models.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ImageUrl {
+    pub url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ContentPart {
+    pub fn image(url: impl Into<String>) -> Self {
+        Self::ImageUrl {
+            image_url: ImageUrl {
+                url: url.into(),
+                detail: None,
+            },
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    /// URLs of the images of every message.
+    pub fn image_urls(&self) -> Vec<String> {
+        self.messages
+            .iter()
+            .flat_map(|message| message.content.to_parts())
+            .filter_map(|part| match part {
+                ContentPart::ImageUrl { image_url } => Some(image_url.url),
+                ContentPart::Text { .. } => None,
+            })
+            .collect()
+    }
+}
+
+impl MessageContent {
+    /// Plain text rendering, images become markdown like in
+    /// `Message::message()`.
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => text.clone(),
+                    ContentPart::ImageUrl { image_url } => format!("![]({})", image_url.url),
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+
+    /// Multi-part rendering, markdown images in text become image parts.
+    pub fn to_parts(&self) -> Vec<ContentPart> {
+        let text = match self {
+            Self::Text(text) => text,
+            Self::Parts(parts) => {
+                return parts
+                    .iter()
+                    .flat_map(|part| match part {
+                        ContentPart::Text { text } => Self::Text(text.clone()).to_parts(),
+                        image => vec![image.clone()],
+                    })
+                    .collect()
+            }
+        };
+
+        let mut parts = vec![];
+        let mut last = 0;
+        for image in MARKDOWN_IMAGE.captures_iter(text) {
+            let whole = image.get(0).unwrap();
+            let before = text[last..whole.start()].trim();
+            if !before.is_empty() {
+                parts.push(ContentPart::Text {
+                    text: before.to_string(),
+                });
+            }
+            parts.push(ContentPart::image(&image[1]));
+            last = whole.end();
+        }
+        let rest = text[last..].trim();
+        if !rest.is_empty() || parts.is_empty() {
+            parts.push(ContentPart::Text {
+                text: rest.to_string(),
+            });
+        }
+        parts
+    }
+
+    /// Whether any image is part of this content.
+    pub fn has_images(&self) -> bool {
+        match self {
+            Self::Text(text) => MARKDOWN_IMAGE.is_match(text),
+            Self::Parts(parts) => parts
+                .iter()
+                .any(|part| matches!(part, ContentPart::ImageUrl { .. })),
+        }
+    }
+}
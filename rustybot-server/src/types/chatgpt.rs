@@ -0,0 +1,84 @@
+//! Conversations as found in `conversations.json` of a ChatGPT data export.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Conversation {
+    #[serde(default)]
+    pub title: Option<String>,
+
+    /// Seconds since epoch.
+    #[serde(default)]
+    pub create_time: Option<f64>,
+
+    /// Messages by node ID, forming a tree through `parent`.
+    pub mapping: HashMap<String, Node>,
+
+    /// Last node of the selected branch.
+    #[serde(default)]
+    pub current_node: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Node {
+    #[serde(default)]
+    pub message: Option<NodeMessage>,
+
+    #[serde(default)]
+    pub parent: Option<String>,
+
+    #[serde(default)]
+    pub children: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct NodeMessage {
+    pub author: Author,
+
+    #[serde(default)]
+    pub create_time: Option<f64>,
+
+    pub content: Content,
+
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Author {
+    pub role: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Content {
+    pub content_type: String,
+
+    /// Strings for text, objects for attachments such as images.
+    #[serde(default)]
+    pub parts: Vec<serde_json::Value>,
+
+    /// Used by `code` content.
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub model_slug: Option<String>,
+}
+
+impl Content {
+    /// Text of the message, attachments left out.
+    pub fn text(&self) -> String {
+        match self.content_type.as_str() {
+            "text" | "multimodal_text" => self
+                .parts
+                .iter()
+                .filter_map(|part| part.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+            _ => self.text.clone().unwrap_or_default(),
+        }
+    }
+}
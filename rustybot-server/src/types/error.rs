@@ -0,0 +1,91 @@
+use actix_web::{http::StatusCode, HttpResponse};
+
+/// Error body in the schema used by the OpenAI API, so clients can handle
+/// our errors and upstream ones the same way.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiError {
+    pub error: ApiErrorInfo,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiErrorInfo {
+    pub message: String,
+
+    #[serde(rename = "type")]
+    pub error_type: String,
+
+    #[serde(default)]
+    pub param: Option<String>,
+
+    /// OpenAI uses both strings and numbers here.
+    #[serde(default)]
+    pub code: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(error_type: &str, message: impl std::fmt::Display) -> Self {
+        Self {
+            error: ApiErrorInfo {
+                message: message.to_string(),
+                error_type: error_type.to_string(),
+                param: None,
+                code: None,
+            },
+        }
+    }
+
+    /// Normalise an upstream error body. Bodies already in the OpenAI schema
+    /// are kept as they are, anything else is wrapped.
+    pub fn from_upstream(status: StatusCode, body: &[u8]) -> Self {
+        if let Ok(error) = serde_json::from_slice::<ApiError>(body) {
+            return error;
+        }
+        let body = String::from_utf8_lossy(body);
+        let message = if body.trim().is_empty() {
+            status
+                .canonical_reason()
+                .unwrap_or("Upstream error")
+                .to_string()
+        } else {
+            body.trim().to_string()
+        };
+        Self::new(Self::type_of(status), message)
+    }
+
+    /// Error type OpenAI would use for the given status.
+    pub fn type_of(status: StatusCode) -> &'static str {
+        match status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::NOT_FOUND => "not_found_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            status if status.is_client_error() => "invalid_request_error",
+            _ => "server_error",
+        }
+    }
+
+    pub fn with_code(mut self, code: &str) -> Self {
+        self.error.code = Some(serde_json::Value::String(code.to_string()));
+        self
+    }
+
+    /// Format as a server-sent event, for streams broken half way.
+    pub fn to_sse(&self) -> String {
+        format!("data: {}\n\n", serde_json::to_string(self).unwrap())
+    }
+
+    pub fn response(&self, status: StatusCode) -> HttpResponse {
+        HttpResponse::build(status).json(self)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.error.error_type, self.error.message)
+    }
+}
+
+/// Shorthand for an error response in the OpenAI schema.
+pub fn error_response(status: StatusCode, message: impl std::fmt::Display) -> HttpResponse {
+    ApiError::new(ApiError::type_of(status), message).response(status)
+}
@@ -1 +1,4 @@
-pub mod version;
\ No newline at end of file
+pub mod chat;
+pub mod chatgpt;
+pub mod error;
+pub mod version;
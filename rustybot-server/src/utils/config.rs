@@ -1,28 +1,430 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+use crate::models::UserRole;
+
+/// Configuration file read when `RUSTYBOT_CONFIG` does not name another one.
+const CONFIG_PATH: &str = "config.yml";
+
+/// Prefix of environment variables overriding configuration values.
+const ENV_PREFIX: &str = "RUSTYBOT_";
+
+/// Server configuration.
+///
+/// Loaded from YAML, then overridden by `RUSTYBOT_*` environment variables
+/// whose name is the path to the value, sections separated by a double
+/// underscore: `RUSTYBOT_SERVER__PORT=8080`, `RUSTYBOT_DATABASE__PASSWORD=...`.
+/// Values are read as YAML, so lists and maps can be given inline, e.g.
+/// `RUSTYBOT_MODELS__NORMAL='[gpt-3.5-turbo*]'`.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
 pub struct Config {
+    #[serde(default)]
+    pub server: Server,
+
+    #[serde(default)]
     pub database: Database,
+
+    #[serde(default)]
+    pub openai: OpenAi,
+
+    #[serde(default)]
+    pub upstream: Upstream,
+
+    #[serde(default)]
+    pub models: ModelPolicy,
+
+    #[serde(default)]
+    pub media: Media,
+
+    #[serde(default)]
+    pub logging: Logging,
+
+    #[serde(default)]
+    pub tracing: Tracing,
+
+    #[serde(default)]
+    pub limits: Limits,
 }
 
+/// Why the configuration could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(String),
+    Invalid(Vec<String>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(path, e) => write!(f, "Unable to read `{}`: {e}", path.display()),
+            Self::Parse(e) => write!(f, "Invalid configuration: {e}"),
+            Self::Invalid(problems) => {
+                writeln!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    writeln!(f, "  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 impl Config {
-    pub fn load() -> Self {
-        let config_path = PathBuf::from("config.yml");
-        if config_path.exists() {
-            let config_content = std::fs::read_to_string(config_path).unwrap();
-            serde_yaml::from_str(&config_content).unwrap()
+    /// Path of the configuration file.
+    pub fn path() -> PathBuf {
+        std::env::var(format!("{ENV_PREFIX}CONFIG"))
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(CONFIG_PATH))
+    }
+
+    /// Load and validate the configuration from its file and the environment.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(&Self::path(), std::env::vars())
+    }
+
+    /// Load and validate the configuration from `path`, overridden by the
+    /// `RUSTYBOT_*` variables of `vars`. A missing file is fine as long as
+    /// the environment provides everything required.
+    pub fn load_from(
+        path: &Path,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut value = if path.exists() {
+            let content =
+                std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+            serde_yaml::from_str(&content)
+                .map_err(|e| ConfigError::Parse(format!("{}: {e}", path.display())))?
         } else {
-            panic!("Config file doesn't exist");
+            serde_yaml::Value::Mapping(Default::default())
+        };
+        if value.is_null() {
+            value = serde_yaml::Value::Mapping(Default::default());
+        }
+
+        // Defaults tell whether a value is text, so `PASSWORD=0123` stays a
+        // string rather than becoming a number.
+        let template = serde_yaml::to_value(Self::default()).unwrap();
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| {
+                name.starts_with(ENV_PREFIX) && name != &format!("{ENV_PREFIX}CONFIG")
+            })
+            .collect();
+        vars.sort();
+        // Path of each overridden value, with the variable overriding it.
+        let mut overridden: Vec<(String, String)> = vec![];
+        for (name, raw) in vars {
+            let keys: Vec<String> = name[ENV_PREFIX.len()..]
+                .split("__")
+                .map(|key| key.to_ascii_lowercase())
+                .collect();
+            override_value(&mut value, &template, &keys, &raw)
+                .map_err(|e| ConfigError::Parse(format!("`{name}`: {e}")))?;
+            overridden.push((keys.join("."), name));
+        }
+
+        // Going through text, so errors tell which value is wrong, and which
+        // variable set it when one did.
+        let merged =
+            serde_yaml::to_string(&value).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let config: Self = serde_yaml::from_str(&merged).map_err(|e| {
+            let e = e.to_string();
+            let path = e.split_once(": ").map(|(path, _)| path).unwrap_or_default();
+            match overridden
+                .iter()
+                .find(|(overridden, _)| same_branch(path, overridden))
+            {
+                Some((_, name)) => ConfigError::Parse(format!("`{name}`: {e}")),
+                None => ConfigError::Parse(e),
+            }
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check values that parse fine but cannot work.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems: Vec<String> = vec![];
+        let mut require = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+
+        require(self.server.port != 0, "`server.port` must not be 0");
+        require(
+            self.server
+                .metrics_token
+                .as_ref()
+                .is_none_or(|token| !token.is_empty()),
+            "`server.metrics_token` must not be empty",
+        );
+        if let Some(tls) = self.server.tls.as_ref() {
+            require(
+                tls.cert.is_file(),
+                &format!("`server.tls.cert`: `{}` is not a file", tls.cert.display()),
+            );
+            require(
+                tls.key.is_file(),
+                &format!("`server.tls.key`: `{}` is not a file", tls.key.display()),
+            );
+            require(
+                tls.port
+                    .is_none_or(|port| port != 0 && port != self.server.port),
+                "`server.tls.port` must not be 0 nor `server.port`",
+            );
+            if let Some(client_ca) = tls.client_ca.as_ref() {
+                require(
+                    client_ca.is_file(),
+                    &format!(
+                        "`server.tls.client_ca`: `{}` is not a file",
+                        client_ca.display()
+                    ),
+                );
+            }
+            require(
+                !tls.require_client_cert || tls.client_ca.is_some(),
+                "`server.tls.require_client_cert` needs `server.tls.client_ca`",
+            );
+        }
+
+        require(
+            !self.database.host.is_empty(),
+            "`database.host` is required",
+        );
+        require(
+            !self.database.username.is_empty(),
+            "`database.username` is required",
+        );
+        require(
+            !self.database.database.is_empty(),
+            "`database.database` is required",
+        );
+        require(
+            self.database.max_connections > 0,
+            "`database.max_connections` must be at least 1",
+        );
+        require(
+            self.database.min_connections <= self.database.max_connections,
+            "`database.min_connections` must not exceed `database.max_connections`",
+        );
+
+        require(
+            !self.openai.api_key.is_empty() || !self.upstream.keys.is_empty(),
+            "`openai.api_key` or `upstream.keys` is required",
+        );
+        require(
+            is_http_url(&self.openai.base_endpoint),
+            "`openai.base_endpoint` must be an http(s) URL",
+        );
+        for (idx, key) in self.upstream.keys.iter().enumerate() {
+            require(
+                !key.api_key.is_empty(),
+                &format!("`upstream.keys[{idx}].api_key` is required"),
+            );
+            require(
+                key.weight > 0,
+                &format!("`upstream.keys[{idx}].weight` must be at least 1"),
+            );
+        }
+        require(
+            self.upstream.connect_timeout > 0,
+            "`upstream.connect_timeout` must be at least 1",
+        );
+        require(
+            self.upstream.read_timeout > 0,
+            "`upstream.read_timeout` must be at least 1",
+        );
+
+        require(
+            self.media.base_url.is_empty() || is_http_url(&self.media.base_url),
+            "`media.base_url` must be empty or an http(s) URL",
+        );
+        require(
+            self.media.max_size > 0,
+            "`media.max_size` must be at least 1",
+        );
+        require(
+            self.media.fetch_timeout > 0,
+            "`media.fetch_timeout` must be at least 1",
+        );
+
+        require(
+            self.logging.level.parse::<log::LevelFilter>().is_ok(),
+            "`logging.level` must be one of off, error, warn, info, debug, trace",
+        );
+
+        if let Some(endpoint) = self.tracing.endpoint.as_ref() {
+            require(
+                is_http_url(endpoint),
+                "`tracing.endpoint` must be an http(s) URL",
+            );
+        }
+        require(
+            (0.0..=1.0).contains(&self.tracing.sample_ratio),
+            "`tracing.sample_ratio` must be between 0 and 1",
+        );
+
+        require(
+            self.limits.json_payload > 0,
+            "`limits.json_payload` must be at least 1",
+        );
+        require(
+            self.limits.import_payload > 0,
+            "`limits.import_payload` must be at least 1",
+        );
+        require(
+            self.limits.audio_upload > 0,
+            "`limits.audio_upload` must be at least 1",
+        );
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
         }
     }
 }
 
+/// Set the value at `keys` from an environment variable, creating sections on
+/// the way.
+fn override_value(
+    value: &mut serde_yaml::Value,
+    template: &serde_yaml::Value,
+    keys: &[String],
+    raw: &str,
+) -> Result<(), String> {
+    let Some((key, rest)) = keys.split_first() else {
+        *value = if template.is_string() {
+            serde_yaml::Value::String(raw.to_string())
+        } else {
+            serde_yaml::from_str(raw).map_err(|e| e.to_string())?
+        };
+        return Ok(());
+    };
+
+    if value.is_null() {
+        *value = serde_yaml::Value::Mapping(Default::default());
+    }
+    let mapping = value
+        .as_mapping_mut()
+        .ok_or_else(|| format!("`{key}` is not inside a section"))?;
+    let entry = mapping
+        .entry(serde_yaml::Value::String(key.clone()))
+        .or_insert(serde_yaml::Value::Null);
+    let template = template
+        .get(key.as_str())
+        .unwrap_or(&serde_yaml::Value::Null);
+    override_value(entry, template, rest, raw)
+}
+
+/// Whether one of two value paths, like `upstream.keys[0].weight`, leads to
+/// the other.
+fn same_branch(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    !short.is_empty()
+        && long
+            .strip_prefix(short)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Where and how the HTTP server listens.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Server {
+    pub host: String,
+
+    pub port: u16,
+
+    /// Worker threads, 0 for one per CPU core.
+    pub workers: usize,
+
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<Tls>,
+
+    /// Seconds given on `SIGTERM` to requests in progress, streams included,
+    /// then to saving their replies, before the process exits.
+    pub shutdown_timeout: u64,
+
+    /// Bearer token `/metrics` is scraped with. Metrics count tokens by
+    /// user, so they are not served without one.
+    pub metrics_token: Option<String>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 9090,
+            workers: 0,
+            tls: None,
+            shutdown_timeout: 30,
+            metrics_token: None,
+        }
+    }
+}
+
+/// HTTPS listener, reloading its certificate when the files change.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct Tls {
+    /// PEM encoded certificate chain.
+    pub cert: PathBuf,
+
+    /// PEM encoded private key.
+    pub key: PathBuf,
+
+    /// Port of the HTTPS listener. When set, plain HTTP is still served on
+    /// `server.port`, otherwise HTTPS replaces it.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// CA certificates client certificates are verified against. A verified
+    /// client is authenticated as the user named by the certificate subject
+    /// common name.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+
+    /// Refuse clients without a certificate, needs `client_ca`.
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
 pub struct Database {
     pub host: String,
     pub username: String,
     pub password: String,
     pub database: String,
+
+    /// Size of the connection pool.
+    pub max_connections: u32,
+
+    /// Connections kept open even when idle.
+    pub min_connections: u32,
+
+    /// Seconds to wait for a free connection.
+    pub acquire_timeout: u64,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            username: String::new(),
+            password: String::new(),
+            database: String::new(),
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout: 30,
+        }
+    }
 }
 
 impl Database {
@@ -32,7 +434,381 @@ impl Database {
             username,
             password,
             database,
+            ..
         } = self;
         format!("mysql://{}:{}@{}/{}", username, password, host, database)
     }
 }
+
+/// OpenAI account, same layout as the `openai` section `rust_ai` reads.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct OpenAi {
+    /// Key used when `upstream.keys` is empty.
+    pub api_key: String,
+
+    pub base_endpoint: String,
+}
+
+impl Default for OpenAi {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            base_endpoint: "https://api.openai.com".to_string(),
+        }
+    }
+}
+
+/// Logging setup.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Logging {
+    /// log4rs configuration file, used when it exists.
+    pub config: PathBuf,
+
+    /// Level of the console logger used without log4rs configuration.
+    pub level: String,
+
+    /// Log message contents, in SQL statements and upstream chunks. Off by
+    /// default, contents are redacted.
+    pub log_content: bool,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            config: PathBuf::from("log4rs.yml"),
+            level: "info".to_string(),
+            log_content: false,
+        }
+    }
+}
+
+/// OpenTelemetry tracing, exported over OTLP/HTTP.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Tracing {
+    /// Base URL of the OTLP collector, e.g. `http://localhost:4318`. Tracing
+    /// is off when unset.
+    pub endpoint: Option<String>,
+
+    pub service_name: String,
+
+    /// Share of traces kept, from 0 to 1.
+    pub sample_ratio: f64,
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "rustybot".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+/// Request size limits, in bytes.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Limits {
+    /// JSON request bodies.
+    pub json_payload: usize,
+
+    /// ChatGPT `conversations.json` imports.
+    pub import_payload: usize,
+
+    /// Transcription uploads, the audio file and its fields together.
+    pub audio_upload: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            json_payload: 2 * 1024 * 1024,
+            import_payload: 64 * 1024 * 1024,
+            audio_upload: 25 * 1024 * 1024,
+        }
+    }
+}
+
+/// Connection settings for the HTTP client talking to OpenAI.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Upstream {
+    /// Seconds to wait for a TCP/TLS connection to be established.
+    pub connect_timeout: u64,
+
+    /// Seconds to wait for the response head, or between two chunks of a
+    /// streamed response body.
+    pub read_timeout: u64,
+
+    /// How many times a request is retried on 429/5xx or connection errors.
+    pub max_retries: u32,
+
+    /// Base delay of the exponential backoff, in milliseconds.
+    pub retry_base_delay: u64,
+
+    /// Upper bound of any single backoff delay (including `Retry-After`), in
+    /// milliseconds.
+    pub retry_max_delay: u64,
+
+    /// Seconds an idle pooled connection is kept alive.
+    pub pool_idle_timeout: u64,
+
+    /// Maximum idle connections kept per host.
+    pub pool_max_idle_per_host: usize,
+
+    /// Pool of OpenAI keys to balance requests over. When empty, the single
+    /// `openai.api_key` is used.
+    pub keys: Vec<UpstreamKey>,
+
+    /// Seconds a key is taken out of rotation after being rate limited,
+    /// unless upstream tells otherwise with `Retry-After`.
+    pub key_cooldown: u64,
+
+    /// Seconds a key is taken out of rotation after its quota is exhausted or
+    /// it has been rejected as invalid.
+    pub key_exhausted_cooldown: u64,
+
+    /// Which upstream response headers are copied to our response.
+    pub forward_headers: HeaderRules,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 10,
+            read_timeout: 60,
+            max_retries: 2,
+            retry_base_delay: 500,
+            retry_max_delay: 10_000,
+            pool_idle_timeout: 90,
+            pool_max_idle_per_host: 32,
+            keys: vec![],
+            key_cooldown: 30,
+            key_exhausted_cooldown: 3600,
+            forward_headers: HeaderRules::default(),
+        }
+    }
+}
+
+/// Where message medias are stored.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Media {
+    /// Directory medias are stored in.
+    pub path: PathBuf,
+
+    /// Public URL of the server, prefixed to `/media/{hash}` in rewritten
+    /// media URLs. Empty keeps them relative.
+    pub base_url: String,
+
+    /// Largest media downloaded or decoded from a `data:` URL, in bytes.
+    pub max_size: usize,
+
+    /// Seconds given to downloading a media, connection included.
+    pub fetch_timeout: u64,
+}
+
+impl Default for Media {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("media"),
+            base_url: String::new(),
+            max_size: 20 * 1024 * 1024,
+            fetch_timeout: 10,
+        }
+    }
+}
+
+/// A single OpenAI key in the upstream pool.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct UpstreamKey {
+    pub api_key: String,
+
+    /// Sent as `OpenAI-Organization` when present.
+    #[serde(default)]
+    pub organization: Option<String>,
+
+    /// Relative share of requests this key receives.
+    #[serde(default = "UpstreamKey::default_weight")]
+    pub weight: u32,
+}
+
+impl UpstreamKey {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+/// Allow/deny lists of header names, matched case-insensitively. A trailing
+/// `*` matches any suffix, e.g. `x-ratelimit-*`.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct HeaderRules {
+    /// Headers that may be forwarded. Empty means every header.
+    pub allow: Vec<String>,
+
+    /// Headers that are never forwarded, even when allowed.
+    pub deny: Vec<String>,
+}
+
+impl Default for HeaderRules {
+    fn default() -> Self {
+        Self {
+            allow: vec![
+                "content-type".to_string(),
+                "openai-model".to_string(),
+                "openai-processing-ms".to_string(),
+                "openai-version".to_string(),
+                "x-request-id".to_string(),
+                "x-ratelimit-*".to_string(),
+                "retry-after".to_string(),
+            ],
+            deny: vec!["set-cookie".to_string(), "openai-organization".to_string()],
+        }
+    }
+}
+
+impl HeaderRules {
+    pub fn allows(&self, name: &str) -> bool {
+        let matches = |pattern: &String| matches_pattern(pattern, name);
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+}
+
+/// Models each user role may use, as lists of model names. A trailing `*`
+/// matches any suffix, e.g. `gpt-3.5-turbo*`. Empty lists allow every model.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ModelPolicy {
+    pub normal: Vec<String>,
+    pub admin: Vec<String>,
+
+    /// Models accepting image inputs, which get image medias as `image_url`
+    /// parts rather than markdown.
+    pub vision: Vec<String>,
+}
+
+impl Default for ModelPolicy {
+    fn default() -> Self {
+        Self {
+            normal: vec![],
+            admin: vec![],
+            vision: vec![
+                "gpt-4-vision*".to_string(),
+                "gpt-4-turbo*".to_string(),
+                "gpt-4o*".to_string(),
+            ],
+        }
+    }
+}
+
+impl ModelPolicy {
+    pub fn supports_vision(&self, model: &str) -> bool {
+        self.vision
+            .iter()
+            .any(|pattern| matches_pattern(pattern, model))
+    }
+
+    pub fn allows(&self, role: &UserRole, model: &str) -> bool {
+        let allowed = match role {
+            UserRole::Normal => &self.normal,
+            UserRole::Admin => &self.admin,
+        };
+        allowed.is_empty()
+            || allowed
+                .iter()
+                .any(|pattern| matches_pattern(pattern, model))
+    }
+}
+
+/// Case-insensitive name matching, a trailing `*` matches any suffix.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name
+            .to_ascii_lowercase()
+            .starts_with(&prefix.to_ascii_lowercase()),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load from the environment `vars` only, with the required values set.
+    fn load(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let required = [
+            ("RUSTYBOT_DATABASE__HOST", "localhost"),
+            ("RUSTYBOT_DATABASE__USERNAME", "rustybot"),
+            ("RUSTYBOT_DATABASE__DATABASE", "rustybot"),
+            ("RUSTYBOT_OPENAI__API_KEY", "sk-test"),
+        ];
+        let vars = required
+            .iter()
+            .chain(vars)
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        Config::load_from(Path::new("/nonexistent/config.yml"), vars)
+    }
+
+    #[test]
+    fn environment_overrides_nested_values() {
+        let config = load(&[
+            ("RUSTYBOT_LOGGING__LOG_CONTENT", "true"),
+            ("RUSTYBOT_UPSTREAM__MAX_RETRIES", "5"),
+            ("RUSTYBOT_UPSTREAM__FORWARD_HEADERS__DENY", "[set-cookie]"),
+            ("RUSTYBOT_MODELS__NORMAL", "[gpt-3.5-turbo*, gpt-4o]"),
+            ("RUSTYBOT_DATABASE__PASSWORD", "0123"),
+            ("OTHER_SERVER__PORT", "1"),
+        ])
+        .unwrap();
+        assert!(config.logging.log_content);
+        assert_eq!(config.upstream.max_retries, 5);
+        assert_eq!(config.upstream.forward_headers.deny, ["set-cookie"]);
+        assert_eq!(config.models.normal, ["gpt-3.5-turbo*", "gpt-4o"]);
+        assert_eq!(config.database.password, "0123");
+        assert_eq!(config.server.port, 9090);
+    }
+
+    #[test]
+    fn bad_value_names_its_variable() {
+        for (name, value) in [
+            ("RUSTYBOT_SERVER__PORT", "eighty"),
+            ("RUSTYBOT_SERVER__PORT", "70000"),
+            ("RUSTYBOT_MODELS__NORMAL", "gpt-4o"),
+            ("RUSTYBOT_SERVER__PORT__NUMBER", "80"),
+        ] {
+            let e = load(&[(name, value)]).unwrap_err().to_string();
+            assert!(e.contains(name), "{name}={value}: {e}");
+        }
+    }
+
+    #[test]
+    fn validation_problems_are_collected() {
+        let mut config = load(&[]).unwrap();
+        config.server.port = 0;
+        config.database.max_connections = 0;
+        config.tracing.sample_ratio = 2.0;
+        config.upstream.keys = vec![UpstreamKey {
+            api_key: String::new(),
+            organization: None,
+            weight: 0,
+        }];
+
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("invalid configuration accepted");
+        };
+        assert_eq!(
+            problems,
+            [
+                "`server.port` must not be 0",
+                "`database.max_connections` must be at least 1",
+                "`upstream.keys[0].api_key` is required",
+                "`upstream.keys[0].weight` must be at least 1",
+                "`tracing.sample_ratio` must be between 0 and 1",
+            ]
+        );
+    }
+}
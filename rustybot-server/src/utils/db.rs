@@ -1,28 +1,13 @@
-use std::sync::{Arc};
-use tokio::sync::{Mutex};
+use sqlx::MySqlPool;
 
-use sqlx::{MySql, Pool};
+use super::config::Database;
 
-use super::config::Config;
-
-lazy_static::lazy_static! {
-  pub static ref DB_POOL: Arc<Mutex<Option<Pool<MySql>>>> = Arc::new(Mutex::new(None));
-}
-
-pub async fn create_pool() -> Result<sqlx::Pool<sqlx::mysql::MySql>, sqlx::Error> {
-    let connection_string = Config::load().database.connection_string();
+/// Connection pool sized by the `database` section. Connections are opened
+/// on first use, so this does not fail when the database is down.
+pub fn create_pool(database: &Database) -> Result<MySqlPool, sqlx::Error> {
     sqlx::mysql::MySqlPoolOptions::new()
-        .max_connections(10)
-        .connect_lazy(&connection_string)
+        .max_connections(database.max_connections)
+        .min_connections(database.min_connections)
+        .acquire_timeout(std::time::Duration::from_secs(database.acquire_timeout))
+        .connect_lazy(&database.connection_string())
 }
-
-pub fn init_pool() {
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(async {
-            let mut db = DB_POOL.lock().await;
-            *db = Some(create_pool().await.unwrap());
-        });
-}
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::{
+    borrow::Cow,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    task::{Context, Poll},
+};
+
+use log4rs::{
+    append::console::ConsoleAppender,
+    config::{Appender, Root},
+    encode::pattern::PatternEncoder,
+    Handle,
+};
+
+use crate::utils::config::Logging;
+
+/// MDC key of the request ID, `{X(request_id)}` in log4rs patterns.
+const REQUEST_ID: &str = "request_id";
+
+/// Handle of the console logger, absent when log4rs is configured by file.
+static CONSOLE: OnceLock<Handle> = OnceLock::new();
+
+/// Whether message contents may appear in logs.
+static LOG_CONTENT: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref SQL_STRING: regex::Regex = regex::Regex::new(r"'(?:[^'\\]|''|\\.)*'").unwrap();
+}
+
+/// Log as configured by the log4rs file, or to the console when there is none.
+pub fn init(logging: &Logging) {
+    set_log_content(logging.log_content);
+    if logging.config.exists() {
+        std::env::set_var("RUST_LOG", "debug");
+        std::env::set_var("RUST_BACKTRACE", "1");
+        log4rs::init_file(&logging.config, Default::default()).unwrap();
+        return;
+    }
+
+    let handle = log4rs::init_config(console_config(&logging.level)).unwrap();
+    let _ = CONSOLE.set(handle);
+}
+
+/// Change the level of the console logger. A log4rs file sets its own
+/// levels, and is reloaded by log4rs itself when it has a `refresh_rate`.
+pub fn set_level(level: &str) {
+    match CONSOLE.get() {
+        Some(handle) => handle.set_config(console_config(level)),
+        None => {
+            log::warn!(target: "app", "`logging.level` is ignored, levels are set by the log4rs configuration file")
+        }
+    }
+}
+
+/// Allow message contents in logs, or redact them.
+pub fn set_log_content(enabled: bool) {
+    LOG_CONTENT.store(enabled, Ordering::Relaxed);
+}
+
+/// `content` as it may be logged.
+pub fn redact(content: &str) -> Cow<'_, str> {
+    if LOG_CONTENT.load(Ordering::Relaxed) {
+        Cow::Borrowed(content)
+    } else {
+        Cow::Owned(format!("<{} bytes redacted>", content.len()))
+    }
+}
+
+/// `sql` as it may be logged: string literals carry message contents, so
+/// they are blanked out unless contents may be logged.
+pub fn redact_sql(sql: &str) -> Cow<'_, str> {
+    if LOG_CONTENT.load(Ordering::Relaxed) {
+        Cow::Borrowed(sql)
+    } else {
+        SQL_STRING.replace_all(sql, "'…'")
+    }
+}
+
+/// ID of the request being served, if any.
+pub fn request_id() -> Option<String> {
+    log_mdc::get(REQUEST_ID, |id| id.map(|id| id.to_string()))
+}
+
+/// Run `future` with `id` as request ID, which tags every line it logs.
+pub fn with_request_id<F: Future>(id: String, future: F) -> WithRequestId<F> {
+    WithRequestId {
+        id,
+        future: Box::pin(future),
+    }
+}
+
+/// Future logging with a request ID, see [`with_request_id()`].
+///
+/// The ID is put in the MDC, which is thread local, for each poll only:
+/// requests served by the same worker thread interleave at every `.await`.
+pub struct WithRequestId<F> {
+    id: String,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _id = enter_request_id(&self.id);
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// Tag what is logged with `id` as request ID until the guard is dropped,
+/// for code polled outside of [`with_request_id()`].
+pub fn enter_request_id(id: &str) -> log_mdc::InsertGuard {
+    log_mdc::insert_scoped(REQUEST_ID, id.to_string())
+}
+
+fn console_config(level: &str) -> log4rs::Config {
+    let console = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(
+            "{d} {l} {t} [{X(request_id)(-)}] - {m}{n}",
+        )))
+        .build();
+    log4rs::Config::builder()
+        .appender(Appender::builder().build("console", Box::new(console)))
+        .build(
+            Root::builder()
+                .appender("console")
+                .build(level.parse().unwrap()),
+        )
+        .unwrap()
+}
@@ -1,5 +1,8 @@
 pub mod config;
 pub mod db;
+pub mod logging;
+pub mod reload;
 pub mod sql;
-
-pub use db::DB_POOL;
\ No newline at end of file
+pub mod tasks;
+pub mod telemetry;
+pub mod tls;
@@ -0,0 +1,247 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use tokio::sync::mpsc;
+
+use crate::{
+    request::RemoteClient,
+    utils::{
+        config::{Config, Limits, Logging, Upstream},
+        logging,
+    },
+};
+
+/// How often the configuration file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Configuration shared with the handlers, swapped as a whole on reload so
+/// a request never sees half of an update.
+pub struct LiveConfig {
+    current: ArcSwap<Config>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(config),
+        }
+    }
+
+    /// Configuration in effect, kept as is for as long as the caller holds it.
+    pub fn get(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    fn store(&self, config: Config) {
+        self.current.store(Arc::new(config));
+    }
+}
+
+/// Reload the configuration when its file changes or on `SIGHUP`.
+///
+/// The new configuration is validated as a whole and ignored when invalid.
+/// Only upstream settings, model allow-lists, limits and logging levels are
+/// applied: the listeners, database pool and media store live on untouched,
+/// and changes to them are reported as needing a restart.
+pub fn watch(live: Arc<LiveConfig>, remote: Arc<RemoteClient>) {
+    let (trigger, mut triggers) = mpsc::channel::<&'static str>(1);
+
+    #[cfg(unix)]
+    tokio::spawn(hangups(trigger.clone()));
+    tokio::spawn(poll(Config::path(), trigger));
+
+    tokio::spawn(async move {
+        while let Some(reason) = triggers.recv().await {
+            log::info!(target: "app", "Reloading configuration ({reason})");
+            reload(&live, &remote);
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn hangups(trigger: mpsc::Sender<&'static str>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!(target: "app", "Unable to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        // A reload already pending will pick the latest file up.
+        let _ = trigger.try_send("SIGHUP");
+    }
+}
+
+async fn poll(path: PathBuf, trigger: mpsc::Sender<&'static str>) {
+    let mut modified = modified_at(&path);
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = modified_at(&path);
+        if now != modified {
+            modified = now;
+            let _ = trigger.try_send("file changed");
+        }
+    }
+}
+
+pub(crate) fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn reload(live: &LiveConfig, remote: &RemoteClient) {
+    let next = match Config::load() {
+        Ok(next) => next,
+        Err(e) => {
+            log::error!(target: "app", "Configuration not reloaded, keeping the current one. {e}");
+            return;
+        }
+    };
+    let current = live.get();
+    let applied = adjustable(&current, next.clone());
+
+    let requested = changes(&current, &next);
+    if requested.is_empty() {
+        log::info!(target: "app", "Configuration unchanged");
+        return;
+    }
+    let effective = changes(&current, &applied);
+    for (path, change) in requested.iter() {
+        if effective.contains_key(path) {
+            log::info!(target: "app", "Configuration `{path}` {change}");
+        } else {
+            log::warn!(target: "app", "Configuration `{path}` {change}, needs a restart to take effect");
+        }
+    }
+
+    if current.logging.level != applied.logging.level {
+        logging::set_level(&applied.logging.level);
+    }
+    logging::set_log_content(applied.logging.log_content);
+    remote.reconfigure(&applied);
+    live.store(applied);
+}
+
+/// `next`, with the settings only read at startup kept from `current`.
+fn adjustable(current: &Config, next: Config) -> Config {
+    Config {
+        server: current.server.clone(),
+        database: current.database.clone(),
+        media: current.media.clone(),
+        tracing: current.tracing.clone(),
+        logging: Logging {
+            config: current.logging.config.clone(),
+            ..next.logging
+        },
+        limits: Limits {
+            json_payload: current.limits.json_payload,
+            ..next.limits
+        },
+        upstream: Upstream {
+            connect_timeout: current.upstream.connect_timeout,
+            pool_idle_timeout: current.upstream.pool_idle_timeout,
+            pool_max_idle_per_host: current.upstream.pool_max_idle_per_host,
+            ..next.upstream
+        },
+        ..next
+    }
+}
+
+/// Values differing between two configurations, by path, described without
+/// revealing secrets.
+fn changes(from: &Config, to: &Config) -> BTreeMap<String, String> {
+    let mut before = BTreeMap::new();
+    let mut after = BTreeMap::new();
+    flatten(&serde_yaml::to_value(from).unwrap(), "", &mut before);
+    flatten(&serde_yaml::to_value(to).unwrap(), "", &mut after);
+
+    let mut changes = BTreeMap::new();
+    for path in before.keys().chain(after.keys()) {
+        let (old, new) = (before.get(path), after.get(path));
+        if old == new || changes.contains_key(path) {
+            continue;
+        }
+        let change = if is_secret(path) {
+            "changed".to_string()
+        } else {
+            let show = |value: Option<&String>| value.cloned().unwrap_or_else(|| "~".to_string());
+            format!("changed from {} to {}", show(old), show(new))
+        };
+        changes.insert(path.clone(), change);
+    }
+    changes
+}
+
+/// Leaves of a YAML value by dotted path. Lists are leaves of their own.
+fn flatten(value: &serde_yaml::Value, path: &str, leaves: &mut BTreeMap<String, String>) {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => {
+            for (key, value) in mapping {
+                let key = key.as_str().unwrap_or_default();
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{path}.{key}")
+                };
+                flatten(value, &path, leaves);
+            }
+        }
+        value => {
+            leaves.insert(path.to_string(), serde_json::to_string(value).unwrap());
+        }
+    }
+}
+
+fn is_secret(path: &str) -> bool {
+    path.ends_with("api_key")
+        || path.ends_with("password")
+        || path.ends_with("token")
+        || path == "upstream.keys"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_masked() {
+        let from = Config::default();
+        let mut to = Config::default();
+        to.openai.api_key = "sk-new".to_string();
+        to.database.password = "hunter2".to_string();
+        to.server.metrics_token = Some("metrics-secret".to_string());
+        to.server.workers = 3;
+
+        let changes = changes(&from, &to);
+        for path in [
+            "openai.api_key",
+            "database.password",
+            "server.metrics_token",
+        ] {
+            assert_eq!(changes[path], "changed", "{path}");
+        }
+        assert_eq!(changes["server.workers"], "changed from 0 to 3");
+        let logged = format!("{changes:?}");
+        for secret in ["sk-new", "hunter2", "metrics-secret"] {
+            assert!(!logged.contains(secret), "{secret}");
+        }
+    }
+
+    #[test]
+    fn secret_paths_are_recognised() {
+        assert!(is_secret("upstream.keys"));
+        assert!(is_secret("server.metrics_token"));
+        assert!(!is_secret("upstream.key_cooldown"));
+        assert!(!is_secret("server.port"));
+    }
+}
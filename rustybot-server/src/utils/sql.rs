@@ -10,3 +10,9 @@ pub fn check_sql_component(raw: &str) -> Result<String, Box<dyn std::error::Erro
         Ok(raw.to_string())
     }
 }
+
+/// Escape text for a single-quoted MySQL string literal. Backslashes go first,
+/// MySQL reading them as escapes by default.
+pub fn escape_string(raw: &str) -> String {
+    raw.replace('\\', "\\\\").replace('\'', "''")
+}
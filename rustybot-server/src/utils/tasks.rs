@@ -0,0 +1,74 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use tokio::{runtime::Handle, sync::Notify};
+
+use crate::utils::logging;
+
+/// Runtime persistence tasks run on. HTTP workers have runtimes of their own,
+/// dropped with any task left when the server stops.
+static RUNTIME: OnceLock<Handle> = OnceLock::new();
+
+lazy_static::lazy_static! {
+    static ref ACTIVE: AtomicUsize = AtomicUsize::new(0);
+    static ref IDLE: Notify = Notify::new();
+}
+
+/// Decrements the active task count however the task ends.
+struct Active;
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        if ACTIVE.fetch_sub(1, Ordering::AcqRel) == 1 {
+            IDLE.notify_waiters();
+        }
+    }
+}
+
+/// Run tasks spawned with [`spawn()`] on the current runtime, which must
+/// outlive the HTTP server.
+pub fn init() {
+    let _ = RUNTIME.set(Handle::current());
+}
+
+/// Spawn a task that must complete before the process exits, such as saving
+/// a streamed reply once the stream ends.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    ACTIVE.fetch_add(1, Ordering::AcqRel);
+    let active = Active;
+    let runtime = RUNTIME.get().cloned().unwrap_or_else(Handle::current);
+    let task = async move {
+        let _active = active;
+        future.await;
+    };
+    // Whatever it logs is still about the request that spawned it.
+    match logging::request_id() {
+        Some(id) => runtime.spawn(logging::with_request_id(id, task)),
+        None => runtime.spawn(task),
+    };
+}
+
+/// Wait for every task spawned with [`spawn()`] to complete, for at most
+/// `timeout`. Returns how many are still running.
+pub async fn flush(timeout: Duration) -> usize {
+    let idle = async {
+        loop {
+            let notified = IDLE.notified();
+            if ACTIVE.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    };
+    let _ = tokio::time::timeout(timeout, idle).await;
+    ACTIVE.load(Ordering::Acquire)
+}
@@ -0,0 +1,48 @@
+use opentelemetry::{
+    sdk::{
+        trace::{self, Sampler},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::layer::SubscriberExt;
+
+use super::config::Tracing;
+
+/// Export spans to the configured OTLP collector. Does nothing when tracing
+/// is off. Must be called from within the Tokio runtime spans are sent from.
+pub fn init(tracing: &Tracing) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(endpoint) = tracing.endpoint.as_ref() else {
+        return Ok(());
+    };
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    tracing.sample_ratio,
+                ))))
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    tracing.service_name.clone(),
+                )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    ::tracing::subscriber::set_global_default(subscriber)?;
+    log::info!(target: "app", "Exporting traces to `{endpoint}`");
+    Ok(())
+}
+
+/// Send the spans still buffered. Blocks until done.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,153 @@
+use std::{any::Any, fs::File, io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use arc_swap::ArcSwap;
+use rustls::{
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        ResolvesServerCert,
+    },
+    sign::CertifiedKey,
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+
+use super::{config::Tls, reload::modified_at};
+
+/// How often the certificate and key files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// User a verified client certificate maps to, from its subject common name.
+/// Stored in the connection data of mutual TLS connections.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity(pub String);
+
+/// Serves the certificate last loaded from the configured PEM files.
+pub struct CertResolver {
+    current: ArcSwap<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn new(tls: &Tls) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            current: ArcSwap::from_pointee(certified_key(tls)?),
+        })
+    }
+
+    /// Reload the certificate whenever its files change. Until both files
+    /// form a valid pair again, the previous certificate keeps being served.
+    pub fn watch(self: Arc<Self>, tls: Tls) {
+        tokio::spawn(async move {
+            let mut modified = (modified_at(&tls.cert), modified_at(&tls.key));
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = (modified_at(&tls.cert), modified_at(&tls.key));
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                match certified_key(&tls) {
+                    Ok(key) => {
+                        self.current.store(Arc::new(key));
+                        log::info!(target: "app", "TLS certificate `{}` reloaded", tls.cert.display());
+                    }
+                    Err(e) => {
+                        log::error!(target: "app", "TLS certificate not reloaded, keeping the current one: {e}")
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
+}
+
+/// Server TLS settings, with certificates served by `resolver` and client
+/// certificates verified against `client_ca` when configured.
+pub fn server_config(
+    tls: &Tls,
+    resolver: Arc<CertResolver>,
+) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match tls.client_ca.as_ref() {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots.add(&cert)?;
+            }
+            if tls.require_client_cert {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            } else {
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+/// Record the identity of a verified client certificate, to be given to
+/// [`actix_web::HttpServer::on_connect()`].
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<actix_tls::accept::rustls::TlsStream<TcpStream>>()
+    else {
+        return;
+    };
+    let Some(cert) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    else {
+        return;
+    };
+    match common_name(cert) {
+        Some(name) => {
+            data.insert(ClientIdentity(name));
+        }
+        None => {
+            log::warn!(target: "app", "Client certificate without a subject common name ignored")
+        }
+    }
+}
+
+fn common_name(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let name = cert.subject().iter_common_name().next()?;
+    name.as_str().ok().map(|name| name.to_string())
+}
+
+fn certified_key(tls: &Tls) -> Result<CertifiedKey, Box<dyn std::error::Error>> {
+    let certs = read_certs(&tls.cert)?;
+
+    let mut key_reader = BufReader::new(File::open(&tls.key)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(format!("No private key in `{}`", tls.key.display()).into()),
+        }
+    };
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| format!("Unsupported private key in `{}`", tls.key.display()))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn std::error::Error>> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("No certificate in `{}`", path.display()).into());
+    }
+    Ok(certs)
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::server;
+use rustybot_server::models::MessageSender;
+use serde_json::json;
+
+#[tokio::test]
+async fn foreign_messages_are_not_read() {
+    let server = server();
+    let owner = server.user("speech_owner").await;
+    let other = server.user("speech_other").await;
+    let chat_id = server.new_chat(&owner).await;
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(owner.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": "Hi"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let reply = server
+        .messages(chat_id)
+        .await
+        .into_iter()
+        .find(|message| matches!(message.msg_sender, MessageSender::Assistant))
+        .unwrap();
+    let msg_id = reply.msg_id.unwrap();
+
+    let mut bodies = vec![];
+    for id in [msg_id, i32::MAX] {
+        let resp = server
+            .post("/v1/audio/speech")
+            .headers(other.headers())
+            .json(&json!({ "msg_id": id }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404, "message {id}");
+        let body: serde_json::Value = resp.json().await.unwrap();
+        bodies.push(body.to_string().replace(&id.to_string(), "{id}"));
+    }
+    assert_eq!(bodies[0], bodies[1]);
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use common::{server, METRICS_TOKEN};
+
+#[tokio::test]
+async fn valid_hash_passes() {
+    let server = server();
+    let user = server.user("auth_valid").await;
+
+    let resp = server
+        .get("/auth/verify")
+        .headers(user.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["result"], "pass");
+}
+
+#[tokio::test]
+async fn wrong_hash_fails() {
+    let server = server();
+    let user = server.user("auth_wrong_hash").await;
+    let hash = user.auth.hash("someone_else", "salt");
+
+    let resp = server
+        .get("/auth/verify")
+        .headers(user.headers_with(&hash, "salt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+}
+
+#[tokio::test]
+async fn unknown_user_fails() {
+    let server = server();
+    let user = server.user("auth_known").await;
+    let mut headers = user.headers();
+    headers.insert("x-rustybot-id", "auth_unknown".parse().unwrap());
+
+    let resp = server
+        .get("/auth/verify")
+        .headers(headers)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+}
+
+#[tokio::test]
+async fn missing_headers_fail() {
+    let server = server();
+
+    let resp = server.get("/auth/verify").send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+}
+
+#[tokio::test]
+async fn api_requires_authentication() {
+    let server = server();
+    let user = server.user("auth_api").await;
+
+    let resp = server
+        .post("/v1/chat/new")
+        .headers(user.headers_with("0000", "salt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    let chats = server
+        .store
+        .chats
+        .find_by_user(user.user.id().unwrap())
+        .await
+        .unwrap();
+    assert!(chats.is_empty());
+}
+
+#[tokio::test]
+async fn probes_need_no_authentication() {
+    let server = server();
+
+    let resp = server.get("/healthz").send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = server.get("/readyz").send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn metrics_need_token() {
+    let server = server();
+    let user = server.user("auth_metrics").await;
+
+    let resp = server.get("/metrics").send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+    let resp = server
+        .get("/metrics")
+        .headers(user.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let resp = server
+        .get("/metrics")
+        .bearer_auth(METRICS_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = resp.text().await.unwrap();
+    assert!(body.contains("rustybot_http_requests_total"));
+}
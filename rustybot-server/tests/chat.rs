@@ -0,0 +1,360 @@
+mod common;
+
+use common::{server, upstream};
+use serde_json::json;
+
+fn completion(model: &str, stream: bool, content: &str) -> serde_json::Value {
+    json!({
+        "model": model,
+        "stream": stream,
+        "messages": [{"role": "user", "content": content}],
+    })
+}
+
+#[tokio::test]
+async fn new_chat_belongs_to_user() {
+    let server = server();
+    let user = server.user("chat_owner").await;
+    let other = server.user("chat_other").await;
+
+    let chat_id = server.new_chat(&user).await;
+    let chat = server
+        .store
+        .chats
+        .find_by_id(chat_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some(chat.chat_user_id), user.user.id());
+
+    let resp = server
+        .get(&format!("/v1/chats/{chat_id}/messages"))
+        .headers(user.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let history: Vec<serde_json::Value> = resp.json().await.unwrap();
+    assert!(history.is_empty());
+
+    let resp = server
+        .get(&format!("/v1/chats/{chat_id}/messages"))
+        .headers(other.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn completion_is_saved() {
+    let server = server();
+    let user = server.user("chat_completion").await;
+    let chat_id = server.new_chat(&user).await;
+
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion("gpt-3.5-turbo", false, "Hello?"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let header = |name: &str| -> Option<i32> {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    };
+    assert_eq!(header("x-rustybot-chat-id"), Some(chat_id));
+    let prompt_id = header("x-rustybot-message-id");
+    let reply_id = header("x-rustybot-reply-id");
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        upstream::COMPLETION_CONTENT
+    );
+
+    let messages = server.messages(chat_id).await;
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].msg_id, prompt_id);
+    assert_eq!(messages[0].msg_sender.role(), "user");
+    assert_eq!(messages[0].msg_content, "Hello?");
+    assert_eq!(messages[1].msg_id, reply_id);
+    assert_eq!(messages[1].msg_sender.role(), "assistant");
+    assert_eq!(messages[1].msg_parent_id, prompt_id);
+    assert_eq!(messages[1].msg_content, upstream::COMPLETION_CONTENT);
+}
+
+#[tokio::test]
+async fn streamed_reply_is_saved() {
+    let server = server();
+    let user = server.user("chat_stream").await;
+    let chat_id = server.new_chat(&user).await;
+
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion("gpt-3.5-turbo", true, "Tell me a story"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+    let body = resp.text().await.unwrap();
+    assert!(body.contains(r#""content":" stream.""#));
+    assert!(body.trim_end().ends_with("data: [DONE]"));
+
+    let messages = server.messages(chat_id).await;
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].msg_content, "Tell me a story");
+    assert_eq!(messages[1].msg_sender.role(), "assistant");
+    assert_eq!(messages[1].msg_parent_id, messages[0].msg_id);
+    assert_eq!(messages[1].msg_content, upstream::STREAM_CONTENT);
+
+    let chat = server
+        .store
+        .chats
+        .find_by_id(chat_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(chat.chat_leaf_msg_id, messages[1].msg_id);
+}
+
+#[tokio::test]
+async fn split_characters_are_saved() {
+    let server = server();
+    let user = server.user("chat_split").await;
+    let chat_id = server.new_chat(&user).await;
+
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion(upstream::SPLIT_MODEL, true, "Say hi"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    resp.text().await.unwrap();
+
+    let messages = server.messages(chat_id).await;
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].msg_content, upstream::SPLIT_CONTENT);
+}
+
+#[tokio::test]
+async fn upstream_error_is_passed_on() {
+    let server = server();
+    let user = server.user("chat_upstream_error").await;
+    let chat_id = server.new_chat(&user).await;
+
+    for stream in [false, true] {
+        let resp = server
+            .post("/v1/chat/completions")
+            .headers(user.headers())
+            .header("x-rustybot-chat-id", chat_id)
+            .json(&completion(upstream::INVALID_MODEL, stream, "Hi"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["code"], "model_not_found");
+        assert_eq!(
+            body["error"]["message"],
+            "The model `mock-invalid` does not exist"
+        );
+    }
+
+    // Prompts are kept, there is no reply to keep.
+    let messages = server.messages(chat_id).await;
+    assert_eq!(messages.len(), 2);
+    assert!(messages
+        .iter()
+        .all(|message| message.msg_sender.role() == "user"));
+}
+
+#[tokio::test]
+async fn unavailable_upstream_is_passed_on() {
+    let server = server();
+    let user = server.user("chat_unavailable").await;
+    let chat_id = server.new_chat(&user).await;
+
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion(upstream::UNAVAILABLE_MODEL, true, "Hi"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 503);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "server_error");
+    assert_eq!(body["error"]["message"], "upstream overloaded");
+
+    let messages = server.messages(chat_id).await;
+    assert_eq!(messages.len(), 1);
+}
+
+#[tokio::test]
+async fn completion_needs_own_chat() {
+    let server = server();
+    let user = server.user("chat_intruder").await;
+    let owner = server.user("chat_victim").await;
+    let chat_id = server.new_chat(&owner).await;
+
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion("gpt-3.5-turbo", false, "Hi"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert!(server.messages(chat_id).await.is_empty());
+}
+
+#[tokio::test]
+async fn internal_media_is_not_fetched() {
+    let server = server();
+    let user = server.user("chat_media").await;
+    let chat_id = server.new_chat(&user).await;
+    let internal = format!("{}/internal", server.upstream);
+
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": internal}},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+            ]}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let messages = server.messages(chat_id).await;
+    let medias = messages[0].msg_medias.as_ref().unwrap();
+    assert_eq!(medias["image_0"].url, internal);
+    assert!(medias["image_1"].url.starts_with("/media/"));
+}
+
+#[tokio::test]
+async fn failed_regeneration_keeps_selection() {
+    let server = server();
+    let user = server.user("chat_regenerate").await;
+    let chat_id = server.new_chat(&user).await;
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion("gpt-3.5-turbo", false, "Hi"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let reply_id = server.messages(chat_id).await[1].msg_id.unwrap();
+    let leaf = || async {
+        server
+            .store
+            .chats
+            .find_by_id(chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .chat_leaf_msg_id
+    };
+
+    let resp = server
+        .post(&format!("/v1/messages/{reply_id}/regenerate"))
+        .headers(user.headers())
+        .json(&json!({"model": upstream::INVALID_MODEL}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(leaf().await, Some(reply_id));
+
+    let resp = server
+        .post(&format!("/v1/messages/{reply_id}/regenerate"))
+        .headers(user.headers())
+        .json(&json!({"model": "gpt-3.5-turbo"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let messages = server.messages(chat_id).await;
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[2].msg_parent_id, messages[0].msg_id);
+    assert_eq!(leaf().await, messages[2].msg_id);
+}
+
+#[tokio::test]
+async fn failed_edit_keeps_selection() {
+    let server = server();
+    let user = server.user("chat_edit").await;
+    let chat_id = server.new_chat(&user).await;
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&completion("gpt-3.5-turbo", false, "Hi"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let messages = server.messages(chat_id).await;
+    let (prompt_id, reply_id) = (messages[0].msg_id.unwrap(), messages[1].msg_id.unwrap());
+    let leaf = || async {
+        server
+            .store
+            .chats
+            .find_by_id(chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .chat_leaf_msg_id
+    };
+
+    let resp = server
+        .post(&format!("/v1/messages/{prompt_id}/edit"))
+        .headers(user.headers())
+        .json(&json!({"model": upstream::INVALID_MODEL, "content": "Hello"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(leaf().await, Some(reply_id));
+
+    let resp = server
+        .post(&format!("/v1/messages/{prompt_id}/edit"))
+        .headers(user.headers())
+        .json(&json!({"model": "gpt-3.5-turbo", "content": "Hello"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let messages = server.messages(chat_id).await;
+    let reply = messages.last().unwrap();
+    assert_eq!(leaf().await, reply.msg_id);
+    let edited = messages
+        .iter()
+        .find(|message| message.msg_id == reply.msg_parent_id)
+        .unwrap();
+    assert_eq!(edited.msg_content, "Hello");
+    assert_eq!(edited.msg_parent_id, None);
+}
@@ -0,0 +1,58 @@
+//! Mock OTLP/HTTP collector, keeping the spans it is sent.
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use opentelemetry_proto::tonic::{
+    collector::trace::v1::ExportTraceServiceRequest, trace::v1::Span,
+};
+use prost::Message;
+
+#[derive(Clone, Default)]
+pub struct Collector {
+    /// Base URL of the collector, as `tracing.endpoint`.
+    pub url: String,
+
+    spans: Arc<Mutex<Vec<Span>>>,
+}
+
+impl Collector {
+    /// Start the collector on a free port of the current runtime.
+    pub fn start() -> std::io::Result<Self> {
+        let spans: Arc<Mutex<Vec<Span>>> = Default::default();
+        let received = web::Data::new(spans.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(received.clone())
+                .route("/v1/traces", web::post().to(export))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))?;
+        let addr = server.addrs()[0];
+        tokio::spawn(server.run());
+        Ok(Self {
+            url: format!("http://{addr}"),
+            spans,
+        })
+    }
+
+    /// Spans received so far.
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+async fn export(body: web::Bytes, spans: web::Data<Arc<Mutex<Vec<Span>>>>) -> HttpResponse {
+    let Ok(request) = ExportTraceServiceRequest::decode(body) else {
+        return HttpResponse::BadRequest().finish();
+    };
+    spans.lock().unwrap().extend(
+        request
+            .resource_spans
+            .into_iter()
+            .flat_map(|resource| resource.scope_spans)
+            .flat_map(|scope| scope.spans),
+    );
+    HttpResponse::Ok().finish()
+}
@@ -0,0 +1,186 @@
+//! Test harness: the server started with `create_server` on an in-memory
+//! store, talking to a mock OpenAI upstream.
+//!
+//! One server is shared by every test of a test binary, so tests create
+//! users of their own rather than expecting an empty store.
+
+#![allow(dead_code)]
+
+pub mod collector;
+pub mod upstream;
+
+use std::{
+    net::{TcpListener, TcpStream},
+    sync::{Arc, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use reqwest::header::{HeaderMap, HeaderValue};
+use rustybot_server::{
+    create_server,
+    models::{repo::MemoryRepo, Auth, Message, Store, User, UserRole},
+    utils::{config::Config, tasks},
+};
+
+static SERVER: OnceLock<TestServer> = OnceLock::new();
+
+/// Token `/metrics` is scraped with.
+pub const METRICS_TOKEN: &str = "metrics-token";
+
+pub struct TestServer {
+    /// Base URL of the server, e.g. `http://127.0.0.1:41234`.
+    pub base: String,
+
+    /// Base URL of the mock upstream.
+    pub upstream: String,
+
+    /// Store the server keeps its models in, to seed and inspect them.
+    pub store: Store,
+
+    client: reqwest::Client,
+}
+
+/// A user of the store, with the key it authenticates with.
+pub struct TestUser {
+    pub user: User,
+    pub auth: Auth,
+}
+
+/// The server shared by the tests of this binary, started on first use.
+pub fn server() -> &'static TestServer {
+    SERVER.get_or_init(start)
+}
+
+/// Serve on a runtime of its own, so the server outlives the runtime of the
+/// test starting it.
+fn start() -> TestServer {
+    let store = Store::memory(Arc::new(MemoryRepo::new()));
+    let port = free_port();
+
+    let served = store.clone();
+    let (upstream_tx, upstream_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let upstream = upstream::start().unwrap();
+            upstream_tx.send(upstream.clone()).unwrap();
+            create_server(config(port, &upstream), served)
+                .await
+                .unwrap();
+        });
+    });
+
+    let upstream = upstream_rx.recv().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "Server did not start listening");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    TestServer {
+        base: format!("http://127.0.0.1:{port}"),
+        upstream,
+        store,
+        client: reqwest::Client::new(),
+    }
+}
+
+fn config(port: u16, upstream: &str) -> Config {
+    let mut config = Config::default();
+    config.server.host = "127.0.0.1".to_string();
+    config.server.port = port;
+    config.server.workers = 2;
+    config.server.shutdown_timeout = 1;
+    config.server.metrics_token = Some(METRICS_TOKEN.to_string());
+    // Never connected to, only there for the configuration to be valid.
+    config.database.host = "127.0.0.1:3306".to_string();
+    config.database.username = "rustybot".to_string();
+    config.database.database = "rustybot".to_string();
+    config.openai.api_key = upstream::API_KEY.to_string();
+    config.openai.base_endpoint = upstream.to_string();
+    // Fail fast on `mock-unavailable`, still going through one retry.
+    config.upstream.max_retries = 1;
+    config.upstream.retry_base_delay = 10;
+    config.media.path = std::env::temp_dir().join(format!("rustybot-test-{port}"));
+    config
+}
+
+/// A port nothing listens on yet.
+fn free_port() -> u16 {
+    TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+impl TestServer {
+    /// Create user `name` together with its auth key.
+    pub async fn user(&self, name: &str) -> TestUser {
+        let user = self
+            .store
+            .users
+            .create(&User::new(name, name, &UserRole::Normal))
+            .await
+            .unwrap();
+        let auth = self.store.auths.find_by_user(&user).await.unwrap();
+        TestUser { user, auth }
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.get(format!("{}{path}", self.base))
+    }
+
+    pub fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.post(format!("{}{path}", self.base))
+    }
+
+    /// Create a chat for `user` through the API.
+    pub async fn new_chat(&self, user: &TestUser) -> i32 {
+        let resp = self
+            .post("/v1/chat/new")
+            .headers(user.headers())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        body["chat_id"].as_i64().unwrap() as i32
+    }
+
+    /// Wait for the background tasks of finished responses, like replies
+    /// still being saved.
+    pub async fn flush(&self) {
+        tasks::flush(Duration::from_secs(5)).await;
+    }
+
+    /// Messages of chat `chat_id`, once the replies still being saved are.
+    pub async fn messages(&self, chat_id: i32) -> Vec<Message> {
+        self.flush().await;
+        self.store.messages.find_by_chat(chat_id).await.unwrap()
+    }
+}
+
+impl TestUser {
+    /// Headers authenticating as this user.
+    pub fn headers(&self) -> HeaderMap {
+        let salt = uuid::Uuid::new_v4().to_string();
+        self.headers_with(&self.auth.hash(&self.user.name(), &salt), &salt)
+    }
+
+    /// Headers claiming to be this user, with any hash.
+    pub fn headers_with(&self, hash: &str, salt: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-rustybot-id",
+            HeaderValue::from_str(&self.user.name()).unwrap(),
+        );
+        headers.insert("x-rustybot-hash", HeaderValue::from_str(hash).unwrap());
+        headers.insert("x-rustybot-salt", HeaderValue::from_str(salt).unwrap());
+        headers
+    }
+}
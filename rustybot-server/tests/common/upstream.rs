@@ -0,0 +1,152 @@
+//! Mock OpenAI upstream, replaying the canned responses of `tests/fixtures`.
+//!
+//! What it answers depends on the requested model:
+//! - `mock-invalid`: 400 with an OpenAI error body.
+//! - `mock-unavailable`: 503 with a plain text body, on every attempt.
+//! - `mock-no-quota`: 429 telling the key ran out of quota.
+//! - `mock-split`: a stream of non-ASCII content, cut every few bytes.
+//! - anything else: the canned completion, streamed when asked to.
+//!
+//! Legacy completions and embeddings get their canned answers, with usage.
+//!
+//! It also serves `/internal`, standing for a service clients must not reach
+//! through us.
+
+use std::time::Duration;
+
+use actix_web::{http::header::ContentType, web, App, HttpRequest, HttpResponse, HttpServer};
+use bytes::Bytes;
+
+/// Key the server must send upstream.
+pub const API_KEY: &str = "sk-mock";
+
+/// Model answered with a 400 error.
+pub const INVALID_MODEL: &str = "mock-invalid";
+
+/// Model answered with a 503 error.
+pub const UNAVAILABLE_MODEL: &str = "mock-unavailable";
+
+/// Model answered with a 429 `insufficient_quota` error.
+pub const NO_QUOTA_MODEL: &str = "mock-no-quota";
+
+/// Model answered with a stream cut in the middle of characters.
+pub const SPLIT_MODEL: &str = "mock-split";
+
+const COMPLETION: &str = include_str!("../fixtures/chat_completion.json");
+const COMPLETION_STREAM: &str = include_str!("../fixtures/chat_completion_stream.txt");
+const LEGACY_COMPLETION: &str = include_str!("../fixtures/completion.json");
+const LEGACY_COMPLETION_STREAM: &str = include_str!("../fixtures/completion_stream.txt");
+const EMBEDDINGS: &str = include_str!("../fixtures/embeddings.json");
+const MODEL_NOT_FOUND: &str = include_str!("../fixtures/model_not_found.json");
+const INSUFFICIENT_QUOTA: &str = include_str!("../fixtures/insufficient_quota.json");
+
+/// Content of the canned completion.
+pub const COMPLETION_CONTENT: &str = "Hello from the mock upstream.";
+
+/// Content of the canned stream, all chunks together.
+pub const STREAM_CONTENT: &str = "Hello from the stream.";
+
+/// Content of the stream of `mock-split`.
+pub const SPLIT_CONTENT: &str = "Grüße, ünïcødé 👋";
+
+/// Start the mock on a free port of the current runtime. Returns its base
+/// URL.
+pub fn start() -> std::io::Result<String> {
+    let server = HttpServer::new(|| {
+        App::new()
+            .route("/v1/chat/completions", web::post().to(chat_completions))
+            .route("/v1/completions", web::post().to(completions))
+            .route(
+                "/v1/embeddings",
+                web::post().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header(ContentType::json())
+                        .body(EMBEDDINGS)
+                }),
+            )
+            .route("/internal", web::get().to(|| async { "internal" }))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))?;
+    let addr = server.addrs()[0];
+    tokio::spawn(server.run());
+    Ok(format!("http://{addr}"))
+}
+
+async fn chat_completions(req: HttpRequest, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let authorized = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        == Some(format!("Bearer {API_KEY}").as_str());
+    if !authorized {
+        return HttpResponse::Unauthorized()
+            .insert_header(ContentType::json())
+            .body(r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#);
+    }
+
+    match body["model"].as_str().unwrap_or_default() {
+        INVALID_MODEL => HttpResponse::BadRequest()
+            .insert_header(ContentType::json())
+            .body(MODEL_NOT_FOUND),
+        UNAVAILABLE_MODEL => HttpResponse::ServiceUnavailable().body("upstream overloaded"),
+        NO_QUOTA_MODEL => HttpResponse::TooManyRequests()
+            .insert_header(ContentType::json())
+            .body(INSUFFICIENT_QUOTA),
+        SPLIT_MODEL => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .streaming(split_events()),
+        _ if body["stream"].as_bool() == Some(true) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .streaming(stream_events(COMPLETION_STREAM)),
+        _ => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(COMPLETION),
+    }
+}
+
+async fn completions(body: web::Json<serde_json::Value>) -> HttpResponse {
+    if body["stream"].as_bool() == Some(true) {
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .streaming(stream_events(LEGACY_COMPLETION_STREAM));
+    }
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body(LEGACY_COMPLETION)
+}
+
+/// Events of the canned stream, one chunk each and a little apart, as they
+/// would come from OpenAI.
+fn stream_events(
+    events: &'static str,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> {
+    async_stream::stream! {
+        for event in events.split("\n\n").filter(|event| !event.trim().is_empty()) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            yield Ok(Bytes::from(format!("{event}\n\n")));
+        }
+    }
+}
+
+/// Events streaming `SPLIT_CONTENT` a word each, sent three bytes at a time so
+/// characters and lines are cut across chunks.
+fn split_events() -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> {
+    let mut events = String::new();
+    for word in SPLIT_CONTENT.split_inclusive(' ') {
+        let chunk = serde_json::json!({
+            "object": "chat.completion.chunk",
+            "model": SPLIT_MODEL,
+            "choices": [{"index": 0, "delta": {"content": word}, "finish_reason": null}],
+        });
+        events.push_str(&format!("data: {chunk}\n\n"));
+    }
+    events.push_str("data: [DONE]\n\n");
+    async_stream::stream! {
+        for piece in events.into_bytes().chunks(3) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            yield Ok(Bytes::copy_from_slice(piece));
+        }
+    }
+}
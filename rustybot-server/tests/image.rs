@@ -0,0 +1,30 @@
+mod common;
+
+use common::server;
+use serde_json::json;
+
+#[tokio::test]
+async fn image_count_is_checked() {
+    let server = server();
+    let user = server.user("image_count").await;
+    let chat_id = server.new_chat(&user).await;
+
+    for n in [
+        json!(0),
+        json!(-1),
+        json!(11),
+        json!(4294967297u64),
+        json!("2"),
+    ] {
+        let resp = server
+            .post("/v1/images/generations")
+            .headers(user.headers())
+            .header("x-rustybot-chat-id", chat_id)
+            .json(&json!({"prompt": "A cat", "n": n}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400, "n {n}");
+    }
+    assert!(server.messages(chat_id).await.is_empty());
+}
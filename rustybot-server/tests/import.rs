@@ -0,0 +1,138 @@
+mod common;
+
+use common::server;
+use serde_json::json;
+
+fn node(role: &str, text: &str, parent: Option<&str>, children: &[&str]) -> serde_json::Value {
+    json!({
+        "message": {
+            "author": {"role": role},
+            "content": {"content_type": "text", "parts": [text]},
+        },
+        "parent": parent,
+        "children": children,
+    })
+}
+
+#[tokio::test]
+async fn conversation_is_imported() {
+    let server = server();
+    let user = server.user("import_tree").await;
+    let export = json!([{
+        "title": "Branches",
+        "mapping": {
+            "q": node("user", "Question", None, &["a1", "a2"]),
+            "a1": node("assistant", "First answer", Some("q"), &[]),
+            "a2": node("assistant", "Second answer", Some("q"), &[]),
+        },
+        "current_node": "a2",
+    }]);
+
+    let resp = server
+        .post("/v1/chats/import")
+        .headers(user.headers())
+        .json(&export)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["chats"][0]["title"], "Branches");
+    assert_eq!(body["chats"][0]["messages"], 3);
+
+    let chat_id = body["chats"][0]["chat_id"].as_i64().unwrap() as i32;
+    let messages = server.messages(chat_id).await;
+    let question = messages
+        .iter()
+        .find(|message| message.msg_content == "Question")
+        .unwrap();
+    assert!(messages
+        .iter()
+        .filter(|message| message.msg_sender.role() == "assistant")
+        .all(|message| message.msg_parent_id == question.msg_id));
+    let chat = server
+        .store
+        .chats
+        .find_by_id(chat_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let leaf = messages
+        .iter()
+        .find(|message| message.msg_content == "Second answer")
+        .unwrap();
+    assert_eq!(chat.chat_leaf_msg_id, leaf.msg_id);
+}
+
+#[tokio::test]
+async fn cyclic_conversation_ends() {
+    let server = server();
+    let user = server.user("import_cycle").await;
+    // `a` lists itself and its parent as children.
+    let export = json!([{
+        "title": "Cycle",
+        "mapping": {
+            "q": node("user", "Question", None, &["a"]),
+            "a": node("assistant", "Answer", Some("q"), &["a", "q"]),
+        },
+    }]);
+
+    let resp = server
+        .post("/v1/chats/import")
+        .headers(user.headers())
+        .json(&export)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["chats"][0]["messages"], 2);
+    let chat_id = body["chats"][0]["chat_id"].as_i64().unwrap() as i32;
+    assert_eq!(server.messages(chat_id).await.len(), 2);
+}
+
+#[tokio::test]
+async fn out_of_range_times_are_dropped() {
+    let server = server();
+    let user = server.user("import_times").await;
+    let mut export = json!([{
+        "title": "Times",
+        "create_time": 1e15,
+        "mapping": {
+            "q": node("user", "Question", None, &["a"]),
+            "a": node("assistant", "Answer", Some("q"), &["b"]),
+            "b": node("user", "Thanks", Some("a"), &[]),
+        },
+    }]);
+    let mapping = &mut export[0]["mapping"];
+    mapping["q"]["message"]["create_time"] = json!(-86400.0);
+    mapping["a"]["message"]["create_time"] = json!(253402300800.0);
+    mapping["b"]["message"]["create_time"] = json!(1700000000.5);
+
+    let resp = server
+        .post("/v1/chats/import")
+        .headers(user.headers())
+        .json(&export)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    let chat_id = body["chats"][0]["chat_id"].as_i64().unwrap() as i32;
+
+    let now = chrono::Utc::now();
+    let chat = server
+        .store
+        .chats
+        .find_by_id(chat_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!((now - chat.chat_created_at).num_minutes() < 1);
+    for message in server.messages(chat_id).await {
+        match message.msg_content.as_str() {
+            "Thanks" => assert_eq!(message.msg_created_at.timestamp(), 1700000000),
+            _ => assert!((now - message.msg_created_at).num_minutes() < 1),
+        }
+    }
+}
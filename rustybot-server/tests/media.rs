@@ -0,0 +1,114 @@
+mod common;
+
+use base64::Engine;
+use common::server;
+use serde_json::json;
+
+/// A 1x1 PNG.
+const PNG: &[u8] =
+    b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0\x1f\x15\xc4\x89";
+
+fn data_url(content_type: &str, body: &[u8]) -> String {
+    format!(
+        "data:{content_type};base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(body)
+    )
+}
+
+/// Send an image to chat `chat_id` and return the URL it was saved with.
+async fn send_image(user: &common::TestUser, chat_id: i32, url: &str) -> Option<String> {
+    let server = server();
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": url}},
+            ]}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let messages = server.messages(chat_id).await;
+    let prompt = messages
+        .iter()
+        .rev()
+        .find(|message| message.msg_sender.role() == "user")
+        .unwrap();
+    prompt
+        .msg_medias
+        .as_ref()
+        .and_then(|medias| medias.get("image_0"))
+        .map(|media| media.url.clone())
+}
+
+#[tokio::test]
+async fn media_is_served_to_owner_only() {
+    let server = server();
+    let owner = server.user("media_owner").await;
+    let other = server.user("media_other").await;
+    let chat_id = server.new_chat(&owner).await;
+
+    let url = send_image(&owner, chat_id, &data_url("image/png", PNG))
+        .await
+        .unwrap();
+    assert!(url.starts_with("/media/"));
+
+    let resp = server
+        .get(&url)
+        .headers(owner.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(header("content-type"), "image/png");
+    assert_eq!(header("x-content-type-options"), "nosniff");
+    assert_eq!(header("content-security-policy"), "sandbox");
+    assert_eq!(resp.bytes().await.unwrap(), PNG);
+
+    let resp = server
+        .get(&url)
+        .headers(other.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    // Referring to it does not make it someone else's.
+    let other_chat_id = server.new_chat(&other).await;
+    assert_eq!(send_image(&other, other_chat_id, &url).await, None);
+    let resp = server
+        .get(&url)
+        .headers(other.headers())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn only_images_are_stored() {
+    let server = server();
+    let user = server.user("media_html").await;
+    let chat_id = server.new_chat(&user).await;
+    let script = b"<svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>";
+
+    for url in [
+        data_url("text/html", b"<script>alert(1)</script>"),
+        data_url("image/svg+xml", script),
+        data_url("image/png", script),
+    ] {
+        assert_eq!(send_image(&user, chat_id, &url).await, Some(url));
+    }
+}
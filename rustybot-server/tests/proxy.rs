@@ -0,0 +1,63 @@
+mod common;
+
+use common::server;
+use rustybot_server::metrics::TOKENS;
+use serde_json::json;
+
+/// Tokens of `kind` counted for `user` with `model`.
+fn tokens(model: &str, user: &common::TestUser, kind: &str) -> u64 {
+    let user = user.user.id().unwrap().to_string();
+    TOKENS.with_label_values(&[model, &user, kind]).get()
+}
+
+#[tokio::test]
+async fn embedding_usage_is_counted() {
+    let server = server();
+    let user = server.user("proxy_embeddings").await;
+
+    let resp = server
+        .post("/v1/embeddings")
+        .headers(user.headers())
+        .json(&json!({"model": "text-embedding-ada-002", "input": "Hello"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(tokens("text-embedding-ada-002", &user, "prompt"), 8);
+}
+
+#[tokio::test]
+async fn legacy_completion_usage_is_counted() {
+    let server = server();
+    let user = server.user("proxy_completions").await;
+    let model = "gpt-3.5-turbo-instruct";
+
+    let resp = server
+        .post("/v1/completions")
+        .headers(user.headers())
+        .json(&json!({"model": model, "prompt": "Hello"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(tokens(model, &user, "prompt"), 5);
+    assert_eq!(tokens(model, &user, "completion"), 7);
+
+    let resp = server
+        .post("/v1/completions")
+        .headers(user.headers())
+        .json(&json!({
+            "model": model,
+            "prompt": "Hello",
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.text().await.unwrap().contains("[DONE]"));
+    server.flush().await;
+    assert_eq!(tokens(model, &user, "prompt"), 9);
+    assert_eq!(tokens(model, &user, "completion"), 10);
+}
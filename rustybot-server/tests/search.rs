@@ -0,0 +1,61 @@
+mod common;
+
+use common::server;
+use serde_json::json;
+
+#[tokio::test]
+async fn whole_words_are_matched() {
+    let server = server();
+    let user = server.user("search_words").await;
+    let chat_id = server.new_chat(&user).await;
+    for content in [
+        "My cat sleeps all day",
+        "How do I concatenate strings?",
+        "<img src=x onerror=alert(1)> tags & markup",
+    ] {
+        let resp = server
+            .post("/v1/chat/completions")
+            .headers(user.headers())
+            .header("x-rustybot-chat-id", chat_id)
+            .json(&json!({
+                "model": "gpt-3.5-turbo",
+                "messages": [{"role": "user", "content": content}],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+    server.messages(chat_id).await;
+
+    let user = &user;
+    let search = |query: &'static str| async move {
+        let resp = server
+            .get("/v1/search")
+            .headers(user.headers())
+            .query(&[("q", query)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        resp.json::<Vec<serde_json::Value>>().await.unwrap()
+    };
+
+    let hits = search("CAT").await;
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0]["snippet"], "My <mark>cat</mark> sleeps all day");
+
+    // Shorter than what a FULLTEXT index keeps.
+    assert!(search("my").await.is_empty());
+
+    let hits = search("cat strings").await;
+    assert_eq!(hits.len(), 2);
+
+    // Message text is never taken for markup.
+    let hits = search("markup").await;
+    assert_eq!(hits.len(), 1);
+    assert_eq!(
+        hits[0]["snippet"],
+        "&lt;img src=x onerror=alert(1)&gt; tags &amp; <mark>markup</mark>"
+    );
+}
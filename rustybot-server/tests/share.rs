@@ -0,0 +1,144 @@
+mod common;
+
+use common::server;
+use serde_json::json;
+
+/// Chat of `user` with one exchange in it.
+async fn chat_with_reply(user: &common::TestUser) -> i32 {
+    let server = server();
+    let chat_id = server.new_chat(user).await;
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": "Hi"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    chat_id
+}
+
+#[tokio::test]
+async fn shared_chat_is_public() {
+    let server = server();
+    let user = server.user("share_public").await;
+    let chat_id = chat_with_reply(&user).await;
+
+    let resp = server
+        .post(&format!("/v1/chats/{chat_id}/shares"))
+        .headers(user.headers())
+        .json(&json!({"expires_in": 3600}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let share: serde_json::Value = resp.json().await.unwrap();
+    assert!(share["expires_at"].is_string());
+
+    let resp = server
+        .get(share["url"].as_str().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn huge_expiry_is_refused() {
+    let server = server();
+    let user = server.user("share_expiry").await;
+    let chat_id = chat_with_reply(&user).await;
+
+    for expires_in in [i64::MAX, i64::MAX / 1000, 400 * 365 * 24 * 3600 * 100] {
+        let resp = server
+            .post(&format!("/v1/chats/{chat_id}/shares"))
+            .headers(user.headers())
+            .json(&json!({ "expires_in": expires_in }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400, "expires_in {expires_in}");
+    }
+}
+
+#[tokio::test]
+async fn malformed_token_is_not_found() {
+    let server = server();
+
+    for token in ["abc", "' OR '1'='1", &"g".repeat(64)] {
+        let resp = server.get(&format!("/share/{token}")).send().await.unwrap();
+        assert_eq!(resp.status(), 404);
+        assert!(server
+            .store
+            .shares
+            .find_by_token(token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
+
+#[tokio::test]
+async fn shared_media_is_sandboxed() {
+    let server = server();
+    let user = server.user("share_media").await;
+    let chat_id = server.new_chat(&user).await;
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+            ]}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let messages = server.messages(chat_id).await;
+    let stored = &messages[0].msg_medias.as_ref().unwrap()["image_0"].url;
+    let hash = stored.strip_prefix("/media/").unwrap();
+
+    let resp = server
+        .post(&format!("/v1/chats/{chat_id}/shares"))
+        .headers(user.headers())
+        .json(&json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let share: serde_json::Value = resp.json().await.unwrap();
+    let token = share["token"].as_str().unwrap();
+
+    let resp = server
+        .get(&format!("/share/{token}/media/{hash}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(header("content-type"), "image/png");
+    assert_eq!(header("x-content-type-options"), "nosniff");
+    assert_eq!(header("content-security-policy"), "sandbox");
+    assert!(header("cache-control").contains("private"));
+
+    let resp = server
+        .get(&format!("/share/{token}/media/{}", "0".repeat(64)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
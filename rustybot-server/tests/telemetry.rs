@@ -0,0 +1,58 @@
+mod common;
+
+use common::{collector::Collector, server};
+use rustybot_server::utils::{config::Tracing, telemetry};
+use serde_json::json;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn completion_is_traced() {
+    let collector = Collector::start().unwrap();
+    telemetry::init(&Tracing {
+        endpoint: Some(collector.url.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let server = server();
+    let user = server.user("telemetry_stream").await;
+    let chat_id = server.new_chat(&user).await;
+    let resp = server
+        .post("/v1/chat/completions")
+        .headers(user.headers())
+        .header("x-rustybot-chat-id", chat_id)
+        .json(&json!({
+            "model": "gpt-3.5-turbo",
+            "stream": true,
+            "messages": [{"role": "user", "content": "Hi"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    resp.text().await.unwrap();
+    server.flush().await;
+    // Exports what is still buffered, waiting on this runtime.
+    tokio::task::spawn_blocking(telemetry::shutdown)
+        .await
+        .unwrap();
+
+    let spans = collector.spans();
+    let request = spans
+        .iter()
+        .find(|span| span.name == "POST /v1/chat/completions")
+        .expect("request span");
+    let upstream = spans
+        .iter()
+        .find(|span| span.name == "post_remote_stream")
+        .expect("upstream span");
+    assert_eq!(upstream.trace_id, request.trace_id);
+    let ttfb = upstream
+        .events
+        .iter()
+        .find(|event| event.name == "first byte from upstream")
+        .expect("ttfb event");
+    assert!(ttfb
+        .attributes
+        .iter()
+        .any(|attribute| attribute.key == "ttfb_ms"));
+}
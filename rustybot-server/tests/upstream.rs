@@ -0,0 +1,41 @@
+mod common;
+
+use common::{server, upstream};
+use rustybot_server::{request::RemoteClient, utils::config::Config};
+use serde_json::json;
+
+/// A client of its own, so keys put on cooldown do not affect other tests.
+fn remote(max_retries: u32) -> RemoteClient {
+    let mut config = Config::default();
+    config.openai.api_key = upstream::API_KEY.to_string();
+    config.openai.base_endpoint = server().upstream.clone();
+    config.upstream.max_retries = max_retries;
+    config.upstream.retry_base_delay = 10;
+    RemoteClient::new(&config).unwrap()
+}
+
+#[tokio::test]
+async fn exhausted_quota_is_told_from_rate_limit() {
+    let request = json!({
+        "model": upstream::NO_QUOTA_MODEL,
+        "messages": [{"role": "user", "content": "Hi"}],
+    });
+
+    // Retries left or not, the key is not tried again.
+    for max_retries in [0, 2] {
+        let remote = remote(max_retries);
+        let resp = remote
+            .post_remote("/v1/chat/completions", &request, None)
+            .await;
+        assert_eq!(resp.status(), 429);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "insufficient_quota");
+
+        let health = &remote.key_health()[0];
+        assert_eq!(health.requests, 1);
+        assert_eq!(health.quota_exhausted, 1);
+        assert_eq!(health.rate_limited, 0);
+        assert_eq!(health.last_error.as_deref(), Some("quota exhausted"));
+    }
+}